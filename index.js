@@ -21,29 +21,321 @@ js.then(js => {
 
     const ctx = canvas.getContext('2d');
 
+    // Keys typed into the settings are not meant for the instrument.
+    const typing = event => event.target.tagName === "INPUT" || event.target.tagName === "SELECT";
+
     document.addEventListener("keydown", event => {
-        console.log(event.shiftKey);
+        if (typing(event)) {
+            return;
+        }
+        if (event.ctrlKey || event.metaKey) {
+            const key = event.key.toLowerCase();
+            const redo = key === "y" || (key === "z" && event.shiftKey);
+            if ((redo && universe.redo()) || (key === "z" && !redo && universe.undo())) {
+                event.preventDefault();
+                render();
+            }
+            return;
+        }
         if (universe.keypress(event.key, event.shiftKey || false)) {
             render();
         }
     });
 
+    document.addEventListener("keyup", event => {
+        if (!typing(event) && universe.keyup(event.key)) {
+            render();
+        }
+    });
+
+    document.querySelector("#performance-mode").addEventListener("input", event => {
+        universe.set_performance_mode(event.target.checked);
+        render();
+    });
+
+    document.querySelector("#bind-key").addEventListener("click", () => {
+        if (universe.bind_key(document.querySelector("#key-binding").value)) {
+            render();
+        }
+    });
+
+    document.querySelector("#unbind-key").addEventListener("click", () => {
+        if (universe.unbind_key(document.querySelector("#key-binding").value)) {
+            render();
+        }
+    });
+
+    document.querySelector("#undo").addEventListener("click", () => {
+        if (universe.undo()) {
+            render();
+        }
+    });
+
+    document.querySelector("#redo").addEventListener("click", () => {
+        if (universe.redo()) {
+            render();
+        }
+    });
+
     document.querySelector("#stop-selected").addEventListener("input", () => {
         universe.stop_selected(document.querySelector("#stop-selected").checked);
     });
 
-    canvas.addEventListener("click", event => {
+    document.querySelector("#tube-radius").addEventListener("change", event => {
+        if (universe.set_radius(parseFloat(event.target.value))) {
+            render();
+        }
+    });
+
+    document.querySelector("#tube-rolloff").addEventListener("change", event => {
+        universe.set_rolloff(parseFloat(event.target.value));
+    });
+
+    document.querySelectorAll(".tube-envelope").forEach(input => {
+        input.addEventListener("change", () => {
+            const value = id => parseFloat(document.querySelector(id).value);
+            universe.set_envelope(
+                value("#envelope-attack"),
+                value("#envelope-decay"),
+                value("#envelope-sustain"),
+                value("#envelope-release"),
+            );
+        });
+    });
+
+    document.querySelector("#tune-selected").addEventListener("click", () => {
+        if (universe.tune_selected(document.querySelector("#tune-note").value)) {
+            render();
+        }
+    });
+
+    document.querySelector("#tuning").addEventListener("change", event => {
+        universe.set_tuning(event.target.value);
+        render();
+    });
+
+    document.querySelector("#reference-pitch").addEventListener("change", event => {
+        universe.set_reference_pitch(parseFloat(event.target.value));
+        render();
+    });
+
+    document.querySelector("#load-scala").addEventListener("change", event => {
+        const files = Array.from(event.target.files);
+        const scl = files.find(f => f.name.endsWith(".scl"));
+        const kbm = files.find(f => f.name.endsWith(".kbm"));
+        if (!scl) {
+            return;
+        }
+        Promise.all([scl.text(), kbm ? kbm.text() : ""]).then(([scl, kbm]) => {
+            if (universe.load_scala(scl, kbm)) {
+                render();
+            }
+        });
+    });
+
+    const setMedium = () => {
+        const gas = document.querySelector("#medium-gas").value;
+        const temperature = parseFloat(document.querySelector("#medium-temperature").value);
+        const humidity = parseFloat(document.querySelector("#medium-humidity").value);
+        const changed = gas === "air"
+            ? universe.set_air(temperature, humidity)
+            : universe.set_gas(gas, temperature);
+        if (changed) {
+            render();
+        }
+    };
+    ["#medium-gas", "#medium-temperature", "#medium-humidity"].forEach(selector => {
+        document.querySelector(selector).addEventListener("change", setMedium);
+    });
+
+    document.querySelector("#sequence-tempo").addEventListener("change", event => {
+        universe.set_tempo(parseFloat(event.target.value));
+        render();
+    });
+
+    document.querySelector("#sequence-play").addEventListener("click", () => {
+        universe.play_sequence();
+        render();
+    });
+
+    document.querySelector("#sequence-stop").addEventListener("click", () => {
+        universe.stop_sequence();
+        render();
+    });
+
+    const recordButton = document.querySelector("#sequence-record");
+    recordButton.addEventListener("click", () => {
+        if (universe.stop_recording()) {
+            recordButton.textContent = "Record";
+        } else {
+            universe.record_sequence();
+            recordButton.textContent = "Stop recording";
+        }
+        render();
+    });
+
+    document.querySelector("#sequence-clear").addEventListener("click", () => {
+        universe.clear_sequence();
+        render();
+    });
+
+    document.querySelector("#add-note").addEventListener("click", () => {
+        const value = id => parseFloat(document.querySelector(id).value);
+        if (universe.add_sequence_note(value("#note-start"), value("#note-duration"), value("#note-velocity"))) {
+            render();
+        }
+    });
+
+    document.querySelector("#load-midi").addEventListener("change", event => {
+        const file = event.target.files[0];
+        if (!file) {
+            return;
+        }
+        file.arrayBuffer().then(buffer => {
+            if (universe.load_midi(new Uint8Array(buffer))) {
+                document.querySelector("#sequence-tempo").value = Math.round(universe.tempo());
+                render();
+            }
+        });
+    });
+
+    // Play the tubes from any connected MIDI keyboard, including ones
+    // plugged in later.
+    if (navigator.requestMIDIAccess) {
+        navigator.requestMIDIAccess().then(access => {
+            const listen = input => {
+                input.onmidimessage = event => {
+                    if (universe.midi_message(event.data)) {
+                        render();
+                    }
+                };
+            };
+            access.inputs.forEach(listen);
+            access.onstatechange = event => {
+                if (event.port.type === "input" && event.port.state === "connected") {
+                    listen(event.port);
+                }
+            };
+        }, () => console.log("MIDI input is unavailable"));
+    }
+
+    // Sequence notes are scheduled a little ahead of the audio clock, so
+    // this only has to run often enough to stay ahead of it.
+    let sequencePlaying = false;
+    setInterval(() => {
+        const playing = universe.tick();
+        if (playing !== sequencePlaying) {
+            sequencePlaying = playing;
+            render();
+        }
+    }, 25);
+
+    const download = (name, data, type) => {
+        const link = document.createElement("a");
+        link.href = URL.createObjectURL(new Blob([data], { type }));
+        link.download = name;
+        link.click();
+        URL.revokeObjectURL(link.href);
+    };
+
+    document.querySelector("#export-svg").addEventListener("click", () => {
+        download("instrument.svg", universe.to_svg(), "image/svg+xml");
+    });
+
+    const wavBits = () => parseInt(document.querySelector("#wav-bits").value);
+
+    document.querySelector("#export-wav").addEventListener("click", () => {
+        const seconds = parseFloat(document.querySelector("#wav-seconds").value);
+        const wav = universe.export_wav(seconds, wavBits());
+        if (wav.length > 0) {
+            download("tubes.wav", wav, "audio/wav");
+        }
+    });
+
+    document.querySelector("#export-sequence-wav").addEventListener("click", () => {
+        const wav = universe.export_sequence_wav(wavBits());
+        if (wav.length > 0) {
+            download("sequence.wav", wav, "audio/wav");
+        }
+    });
+
+    document.querySelector("#export-midi").addEventListener("click", () => {
+        download("performance.mid", universe.export_midi(), "audio/midi");
+    });
+
+    document.querySelector("#clear-performance").addEventListener("click", () => {
+        universe.clear_performance();
+    });
+
+    document.querySelector("#save-layout").addEventListener("click", () => {
+        download("instrument.json", universe.to_json(), "application/json");
+    });
+
+    document.querySelector("#load-layout").addEventListener("change", event => {
+        const file = event.target.files[0];
+        if (!file) {
+            return;
+        }
+        const reader = new FileReader();
+        reader.onload = () => {
+            if (universe.load_json(reader.result)) {
+                const layout = JSON.parse(reader.result);
+                document.querySelector("#stop-selected").checked = layout.stop_selected;
+                if (layout.sequence) {
+                    document.querySelector("#sequence-tempo").value = layout.sequence.tempo;
+                }
+                recordButton.textContent = "Record";
+                render();
+            }
+        };
+        reader.readAsText(file);
+    });
+
+    const canvasPoint = event => {
         const boundingRect = canvas.getBoundingClientRect();
         const scaleX = canvas.width / boundingRect.width;
         const scaleY = canvas.height / boundingRect.height;
 
-        const canvasLeft = (event.clientX - boundingRect.left) * scaleX;
-        const canvasTop = (event.clientY - boundingRect.top) * scaleY;
+        return [
+            (event.clientX - boundingRect.left) * scaleX,
+            (event.clientY - boundingRect.top) * scaleY,
+        ];
+    };
+
+    // A drag that changed a tube is followed by a click we should ignore.
+    let dragged = false;
+
+    canvas.addEventListener("pointerdown", event => {
+        const [x, y] = canvasPoint(event);
+        if (universe.pointer_down(x, y, event.shiftKey)) {
+            canvas.setPointerCapture(event.pointerId);
+        }
+    });
+
+    canvas.addEventListener("pointermove", event => {
+        const [x, y] = canvasPoint(event);
+        if (universe.pointer_move(x, y)) {
+            render();
+        }
+    });
+
+    canvas.addEventListener("pointerup", event => {
+        const [x, y] = canvasPoint(event);
+        dragged = universe.pointer_up(x, y);
+        render();
+    });
+
+    canvas.addEventListener("click", event => {
+        if (dragged) {
+            dragged = false;
+            return;
+        }
+        const [canvasLeft, canvasTop] = canvasPoint(event);
 
         const row = Math.min(Math.floor(canvasTop / CELL_SIZE), canvas.height);
         const col = Math.min(Math.floor(canvasLeft / CELL_SIZE), canvas.width);
 
-        if (universe.clicked(row, col, Math.round(canvasLeft), Math.round(canvasTop))) {
+        if (universe.clicked(row, col, Math.round(canvasLeft), Math.round(canvasTop), event.shiftKey)) {
             render();
         }
     });
@@ -55,6 +347,23 @@ js.then(js => {
         //window.raf_id = requestAnimationFrame(render);
     };
 
+    // The analysis panel follows the output, so redraw it every frame
+    // while it is shown.
+    let analysisFrame = null;
+    const animate = () => {
+        render();
+        analysisFrame = requestAnimationFrame(animate);
+    };
+    document.querySelector("#show-analysis").addEventListener("input", event => {
+        universe.set_analysis(event.target.checked);
+        cancelAnimationFrame(analysisFrame);
+        if (event.target.checked) {
+            animate();
+        } else {
+            render();
+        }
+    });
+
     window.js = js;
     window.memory = memory;
     window.render =render;
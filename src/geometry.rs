@@ -0,0 +1,524 @@
+use std::f64::consts::PI;
+use std::fmt;
+use std::ops::{Add, Mul, Sub};
+
+use surface::Surface;
+
+/// Tolerance, in pixels, within which points count as lying on a line.
+pub const EPSILON: f64 = 1e-9;
+
+#[derive(PartialEq, Copy, Clone, Serialize, Deserialize)]
+pub struct Point {
+    pub x: f64,
+    pub y: f64,
+}
+
+impl fmt::Debug for Point {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "({}, {})", self.x, self.y)
+    }
+}
+
+impl Point {
+    pub fn origin() -> Point {
+        Point { x: 0.0, y: 0.0 }
+    }
+
+    pub fn distance_to(self, o: Point) -> f64 {
+        ((self.x - o.x).powi(2) + (self.y - o.y).powi(2)).sqrt()
+    }
+
+    pub fn cross(self, o: Point) -> f64 {
+        self.x * o.y - o.x * self.y
+    }
+
+    pub fn rotate(self, angle: f64) -> Point {
+        let Point { x, y } = self;
+        Point {
+            x: x * angle.cos() - y * angle.sin(),
+            y: x * angle.sin() + y * angle.cos(),
+        }
+    }
+}
+
+impl Add<Vector2> for Point {
+    type Output = Point;
+
+    fn add(self, other: Vector2) -> Point {
+        Point {
+            x: (self.x + other.x),
+            y: (self.y + other.y),
+        }
+    }
+}
+
+/// `a - b` is the vector from `b` to `a`.
+impl Sub<Point> for Point {
+    type Output = Vector2;
+
+    fn sub(self, other: Point) -> Vector2 {
+        Vector2 {
+            x: self.x - other.x,
+            y: self.y - other.y,
+        }
+    }
+}
+
+#[derive(PartialEq, Copy, Clone, Serialize, Deserialize)]
+pub struct Vector2 {
+    pub x: f64,
+    pub y: f64,
+}
+
+impl fmt::Debug for Vector2 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<{}, {}>", self.x, self.y)
+    }
+}
+
+impl Mul<f64> for Vector2 {
+    type Output = Vector2;
+
+    fn mul(self, other: f64) -> Vector2 {
+        Vector2 {
+            x: self.x * other,
+            y: self.y * other,
+        }
+    }
+}
+
+impl Vector2 {
+    pub fn to_point(pt: Point) -> Vector2 {
+        Vector2::from_segment(Segment::new(pt, Point { x: 0.0, y: 0.0 }))
+    }
+
+    pub fn i() -> Vector2 {
+        Vector2 { x: 1.0, y: 0.0 }
+    }
+
+    pub fn j() -> Vector2 {
+        Vector2 { x: 0.0, y: 1.0 }
+    }
+
+    /// Straight up the screen, where y grows downward.
+    pub fn up() -> Vector2 {
+        Vector2 { x: 0.0, y: -1.0 }
+    }
+
+    pub fn angle_to(self, v: Vector2) -> f64 {
+        (self.dot(v) / (self.magnitude() * v.magnitude())).acos()
+    }
+
+    pub fn anchor_at(self, p: Point) -> Segment {
+        Segment {
+            from: p,
+            to: p + self,
+        }
+    }
+
+    pub fn dot(self, o: Vector2) -> f64 {
+        self.x * o.x + self.y * o.y
+    }
+
+    /// The z component of the 3D cross product. On screen, where y grows
+    /// downward, it is positive when `o` turns clockwise from `self`.
+    pub fn cross(self, o: Vector2) -> f64 {
+        self.x * o.y - o.x * self.y
+    }
+
+    pub fn project_onto(self, b: Vector2) -> Vector2 {
+        b * (self.dot(b) / b.dot(b))
+    }
+
+    /// The vector from `s.to` back to `s.from`; see `Segment::direction` for
+    /// the other way round.
+    pub fn from_segment(s: Segment) -> Vector2 {
+        s.from - s.to
+    }
+
+    pub fn orthogonal_cw(self) -> Vector2 {
+        Vector2 {
+            x: self.y,
+            y: -self.x,
+        }
+    }
+
+    pub fn orthogonal_ccw(self) -> Vector2 {
+        Vector2 {
+            x: -self.y,
+            y: self.x,
+        }
+    }
+
+    pub fn magnitude(self) -> f64 {
+        (self.x.powi(2) + self.y.powi(2)).sqrt()
+    }
+
+    pub fn with_length(self, length: f64) -> Vector2 {
+        self * (length / self.magnitude())
+    }
+}
+
+/// Which way a path turns, as seen on screen.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Orientation {
+    Clockwise,
+    CounterClockwise,
+    Collinear,
+}
+
+/// Which way the path `a`, `b`, `c` turns at `b`.
+pub fn orientation(a: Point, b: Point, c: Point) -> Orientation {
+    let cross = (b - a).cross(c - a);
+    // Scale the tolerance so long segments are not held to a tighter one.
+    let tolerance = EPSILON * (b - a).magnitude().max((c - a).magnitude()).max(1.0);
+    if cross > tolerance {
+        Orientation::Clockwise
+    } else if cross < -tolerance {
+        Orientation::CounterClockwise
+    } else {
+        Orientation::Collinear
+    }
+}
+
+/// Is p1 between p2 and p3?
+pub fn between(p1: f64, p2: f64, p3: f64) -> bool {
+    (p2 < p1 && p1 < p3) || (p3 < p1 && p1 < p2) || p1 == p2 || p1 == p3
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Segment {
+    pub from: Point,
+    pub to: Point,
+}
+
+impl Segment {
+    pub fn new(a: Point, b: Point) -> Self {
+        Segment { from: a, to: b }
+    }
+
+    pub fn midpoint(self) -> Point {
+        Point {
+            x: (self.from.x + self.to.x) / 2.0,
+            y: (self.from.y + self.to.y) / 2.0,
+        }
+    }
+
+    pub fn draw<S: Surface>(self, ctx: &mut S, color: &str) {
+        Circle::new(self.from, 4.0).draw(ctx, "grey");
+        Circle::new(self.to, 4.0).draw(ctx, "purple");
+        ctx.begin_path();
+        ctx.move_to(self.from.x, self.from.y);
+        ctx.line_to(self.to.x, self.to.y);
+        ctx.set_stroke_style(color);
+        ctx.set_line_width(3.0);
+        ctx.stroke();
+    }
+
+    /// The rectangle `width` wide that has this segment as its axis.
+    pub fn to_rectangle(self, width: f64) -> Rectangle {
+        Rectangle {
+            base_a: self.from,
+            base_b: self.to,
+            height: width,
+        }
+    }
+
+    pub fn length(self) -> f64 {
+        self.from.distance_to(self.to)
+    }
+
+    /// The vector from `from` to `to`.
+    pub fn direction(self) -> Vector2 {
+        self.to - self.from
+    }
+
+    /// The point of the segment nearest to `p`.
+    pub fn closest_point(self, p: Point) -> Point {
+        let d = self.direction();
+        let length_squared = d.dot(d);
+        if length_squared == 0.0 {
+            return self.from;
+        }
+        let t = ((p - self.from).dot(d) / length_squared).max(0.0).min(1.0);
+        self.from + d * t
+    }
+
+    pub fn distance_to(self, p: Point) -> f64 {
+        p.distance_to(self.closest_point(p))
+    }
+
+    /// Whether `needle` lies on the segment, ends included.
+    pub fn contains(self, needle: Point) -> bool {
+        self.distance_to(needle) <= EPSILON * self.length().max(1.0)
+    }
+
+    pub fn is_parallel(self, other: Self) -> bool {
+        let (a, b) = (self.direction(), other.direction());
+        a.cross(b).abs() <= EPSILON * a.magnitude().max(b.magnitude()).max(1.0)
+    }
+
+    /// Whether the segments share at least one point, including when they
+    /// merely touch or overlap along a common line.
+    pub fn intersects(self, other: Self) -> bool {
+        let o1 = orientation(self.from, self.to, other.from);
+        let o2 = orientation(self.from, self.to, other.to);
+        let o3 = orientation(other.from, other.to, self.from);
+        let o4 = orientation(other.from, other.to, self.to);
+        let crosses = o1 != o2
+            && o3 != o4
+            && [o1, o2, o3, o4]
+                .iter()
+                .all(|&o| o != Orientation::Collinear);
+        crosses
+            || self.contains(other.from)
+            || self.contains(other.to)
+            || other.contains(self.from)
+            || other.contains(self.to)
+    }
+
+    /// A point the segments share. Overlapping collinear segments share
+    /// many; then an end of one that lies on the other is returned.
+    pub fn intersection(self, other: Self) -> Option<Point> {
+        if !self.intersects(other) {
+            return None;
+        }
+        if self.is_parallel(other) {
+            return [other.from, other.to]
+                .iter()
+                .cloned()
+                .find(|&p| self.contains(p))
+                .or_else(|| {
+                    [self.from, self.to]
+                        .iter()
+                        .cloned()
+                        .find(|&p| other.contains(p))
+                });
+        }
+        let (d, e) = (self.direction(), other.direction());
+        let t = (other.from - self.from).cross(e) / d.cross(e);
+        Some(self.from + d * t)
+    }
+}
+
+/// A rectangle standing on the segment from `base_a` to `base_b`, which
+/// runs through its middle; `height` is its full extent across that axis.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Rectangle {
+    pub base_a: Point,
+    pub base_b: Point,
+    pub height: f64,
+}
+
+impl Rectangle {
+    pub fn contains(&self, needle: Point) -> bool {
+        let axis = self.base_b - self.base_a;
+        let length = axis.magnitude();
+        let offset = needle - self.base_a;
+        if length == 0.0 {
+            return offset.magnitude() <= self.height / 2.0;
+        }
+        let along = offset.dot(axis) / length;
+        let across = offset.cross(axis).abs() / length;
+        along >= 0.0 && along <= length && across <= self.height / 2.0
+    }
+
+    pub fn center(&self) -> Point {
+        Segment::new(self.base_a, self.base_b).midpoint()
+    }
+
+    pub fn draw<S: Surface>(&self, ctx: &mut S, color: &str) {
+        let v = Vector2::from_segment(Segment {
+            from: self.base_a,
+            to: self.base_b,
+        });
+        let v1 = v.orthogonal_ccw().with_length(self.height / 2.0);
+        let v2 = v.orthogonal_cw().with_length(self.height / 2.0);
+        let p1 = self.base_a + v1;
+        let p2 = self.base_a + v2;
+        let p3 = self.base_b + v2;
+        let p4 = self.base_b + v1;
+
+        ctx.set_line_width(4.0);
+        ctx.begin_path();
+        ctx.move_to(p1.x, p1.y);
+        ctx.line_to(p2.x, p2.y);
+        ctx.line_to(p3.x, p3.y);
+        ctx.move_to(p4.x, p4.y);
+        ctx.line_to(p1.x, p1.y);
+        ctx.set_stroke_style(color);
+        ctx.stroke();
+    }
+}
+
+pub struct Circle {
+    x: f64,
+    y: f64,
+    radius: f64,
+}
+
+impl Circle {
+    pub fn new(Point { x, y }: Point, radius: f64) -> Circle {
+        Circle { x, y, radius }
+    }
+
+    pub fn draw<S: Surface>(&self, ctx: &mut S, color: &str) {
+        ctx.begin_path();
+        ctx.move_to(self.x, self.y);
+        ctx.arc(self.x, self.y, self.radius, 0.0, PI * 2.0, false);
+        ctx.set_fill_style(color);
+        ctx.fill();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn p(x: f64, y: f64) -> Point {
+        Point { x, y }
+    }
+
+    fn s(x1: f64, y1: f64, x2: f64, y2: f64) -> Segment {
+        Segment::new(p(x1, y1), p(x2, y2))
+    }
+
+    /// Deterministic pseudo-random segments with coordinates on a small
+    /// grid, so collinear and touching cases come up often.
+    fn segments(count: usize) -> Vec<Segment> {
+        let mut state: u32 = 12345;
+        let mut next = || {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            ((state >> 16) % 11) as f64 - 5.0
+        };
+        (0..count)
+            .map(|_| s(next(), next(), next(), next()))
+            .collect()
+    }
+
+    #[test]
+    fn orientation_on_screen() {
+        // y grows downward, so right then down turns clockwise.
+        let (a, b) = (p(0.0, 0.0), p(1.0, 0.0));
+        assert_eq!(orientation(a, b, p(1.0, 1.0)), Orientation::Clockwise);
+        assert_eq!(
+            orientation(a, b, p(1.0, -1.0)),
+            Orientation::CounterClockwise
+        );
+        assert_eq!(orientation(a, b, p(5.0, 0.0)), Orientation::Collinear);
+        assert_eq!(orientation(a, a, p(3.0, 4.0)), Orientation::Collinear);
+    }
+
+    #[test]
+    fn crossing_segments() {
+        let (a, b) = (s(0.0, 0.0, 4.0, 4.0), s(0.0, 4.0, 4.0, 0.0));
+        assert!(a.intersects(b));
+        assert_eq!(a.intersection(b), Some(p(2.0, 2.0)));
+        assert!(!a.intersects(s(5.0, 0.0, 9.0, 0.0)));
+        assert_eq!(a.intersection(s(5.0, 0.0, 9.0, 0.0)), None);
+    }
+
+    #[test]
+    fn touching_endpoints() {
+        let a = s(0.0, 0.0, 4.0, 0.0);
+        assert_eq!(a.intersection(s(4.0, 0.0, 4.0, 3.0)), Some(p(4.0, 0.0)));
+        // An end resting on the middle of the other segment.
+        assert_eq!(a.intersection(s(2.0, 3.0, 2.0, 0.0)), Some(p(2.0, 0.0)));
+    }
+
+    #[test]
+    fn collinear_segments() {
+        let a = s(0.0, 0.0, 4.0, 0.0);
+        let overlapping = s(2.0, 0.0, 6.0, 0.0);
+        assert!(a.intersects(overlapping));
+        assert_eq!(a.intersection(overlapping), Some(p(2.0, 0.0)));
+        assert!(!a.intersects(s(5.0, 0.0, 8.0, 0.0)));
+    }
+
+    #[test]
+    fn parallel_segments() {
+        let a = s(0.0, 0.0, 4.0, 0.0);
+        let b = s(0.0, 1.0, 4.0, 1.0);
+        assert!(a.is_parallel(b));
+        assert!(!a.intersects(b));
+        assert_eq!(a.intersection(b), None);
+    }
+
+    #[test]
+    fn degenerate_segments() {
+        let a = s(0.0, 0.0, 4.0, 0.0);
+        let point = s(1.0, 0.0, 1.0, 0.0);
+        assert!(a.intersects(point) && point.intersects(a));
+        assert_eq!(a.intersection(point), Some(p(1.0, 0.0)));
+        assert!(!a.intersects(s(1.0, 1.0, 1.0, 1.0)));
+        assert_eq!(point.closest_point(p(5.0, 5.0)), p(1.0, 0.0));
+        assert_eq!(point.length(), 0.0);
+    }
+
+    #[test]
+    fn closest_point_and_distance() {
+        let a = s(0.0, 0.0, 4.0, 0.0);
+        assert_eq!(a.closest_point(p(2.0, 3.0)), p(2.0, 0.0));
+        assert_eq!(a.closest_point(p(-2.0, 3.0)), p(0.0, 0.0));
+        assert_eq!(a.closest_point(p(7.0, -4.0)), p(4.0, 0.0));
+        assert_eq!(a.distance_to(p(7.0, -4.0)), 5.0);
+        assert_eq!(a.distance_to(p(1.0, 0.0)), 0.0);
+    }
+
+    #[test]
+    fn rectangle_contains() {
+        let r = s(0.0, 0.0, 0.0, -10.0).to_rectangle(4.0);
+        assert!(r.contains(p(0.0, -5.0)));
+        assert!(r.contains(p(2.0, 0.0)));
+        assert!(!r.contains(p(2.1, -5.0)));
+        assert!(!r.contains(p(0.0, 1.0)));
+        assert!(!r.contains(p(0.0, -10.1)));
+        // Tilted by 45 degrees.
+        let r = s(0.0, 0.0, 10.0, 10.0).to_rectangle(2.0);
+        assert!(r.contains(p(5.5, 5.0)));
+        assert!(!r.contains(p(7.0, 5.0)));
+        // A rectangle of no length is a disc.
+        let r = s(1.0, 1.0, 1.0, 1.0).to_rectangle(2.0);
+        assert!(r.contains(p(1.5, 1.5)));
+        assert!(!r.contains(p(2.0, 2.0)));
+    }
+
+    #[test]
+    fn intersects_is_symmetric() {
+        let segments = segments(60);
+        for &a in &segments {
+            for &b in &segments {
+                assert_eq!(a.intersects(b), b.intersects(a), "{:?} {:?}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn intersection_lies_on_both_segments() {
+        let segments = segments(60);
+        for &a in &segments {
+            for &b in &segments {
+                if let Some(x) = a.intersection(b) {
+                    assert!(a.distance_to(x) < 1e-6 && b.distance_to(x) < 1e-6);
+                }
+                assert_eq!(a.intersects(b), a.intersection(b).is_some());
+            }
+        }
+    }
+
+    #[test]
+    fn closest_point_lies_on_segment() {
+        let segments = segments(60);
+        let points = segments
+            .iter()
+            .map(|s| s.midpoint() + Vector2 { x: 3.0, y: -2.0 });
+        for (&segment, point) in segments.iter().zip(points) {
+            let closest = segment.closest_point(point);
+            assert!(segment.contains(closest), "{:?} {:?}", segment, closest);
+            // No end is nearer than the closest point.
+            let distance = segment.distance_to(point);
+            assert!(distance <= point.distance_to(segment.from) + 1e-9);
+            assert!(distance <= point.distance_to(segment.to) + 1e-9);
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use geometry::Point;
+use layout::TubeLayout;
+use sequencer::Sequence;
+
+/// How many edits are remembered before the oldest are forgotten.
+const LIMIT: usize = 500;
+
+/// A reversible change to the instrument, addressing tubes by index.
+/// Applying an edit yields the edit that reverts it.
+#[derive(Debug, Clone)]
+pub enum Edit {
+    Insert {
+        index: usize,
+        tube: TubeLayout,
+    },
+    Remove {
+        index: usize,
+    },
+    SetLength {
+        index: usize,
+        length: f64,
+    },
+    Move {
+        index: usize,
+        from: Point,
+    },
+    SetOpen {
+        index: usize,
+        open: bool,
+    },
+    SetMode {
+        index: usize,
+        mode: u32,
+    },
+    SetPlaying {
+        index: usize,
+        playing: bool,
+    },
+    /// Replaces the whole sequence, stopping it if it is playing.
+    SetSequence(Sequence),
+    /// Several edits applied in order as one step.
+    Group(Vec<Edit>),
+}
+
+/// Undo and redo stacks of inverse edits.
+#[derive(Default)]
+pub struct History {
+    undo: Vec<Edit>,
+    redo: Vec<Edit>,
+}
+
+impl History {
+    /// Remembers how to revert a new user edit, which invalidates any
+    /// edits that were undone before it.
+    pub fn record(&mut self, inverse: Edit) {
+        self.redo.clear();
+        self.push_undo(inverse);
+    }
+
+    pub fn push_undo(&mut self, inverse: Edit) {
+        self.undo.push(inverse);
+        if self.undo.len() > LIMIT {
+            self.undo.remove(0);
+        }
+    }
+
+    pub fn push_redo(&mut self, inverse: Edit) {
+        self.redo.push(inverse);
+    }
+
+    pub fn pop_undo(&mut self) -> Option<Edit> {
+        self.undo.pop()
+    }
+
+    pub fn pop_redo(&mut self) -> Option<Edit> {
+        self.redo.pop()
+    }
+
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+    }
+}
@@ -0,0 +1,83 @@
+use std::collections::BTreeMap;
+
+/// Keyboard keys bound to the tubes they play in performance mode.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Keymap {
+    /// Tube ids by key, named as by `KeyboardEvent.key` in lower case so
+    /// holding shift does not matter.
+    keys: BTreeMap<String, u32>,
+}
+
+/// The home row, bound to the tubes of the default instrument.
+impl Default for Keymap {
+    fn default() -> Keymap {
+        let mut keymap = Keymap {
+            keys: BTreeMap::new(),
+        };
+        for (i, key) in ["a", "s", "d", "f", "g", "h", "j", "k"].iter().enumerate() {
+            keymap.bind(key, i as u32 + 1);
+        }
+        keymap
+    }
+}
+
+impl Keymap {
+    /// The tube `key` plays, if it is bound.
+    pub fn tube(&self, key: &str) -> Option<u32> {
+        self.keys.get(&key.to_lowercase()).cloned()
+    }
+
+    /// Makes `key` play the tube with id `tube`, instead of any other.
+    pub fn bind(&mut self, key: &str, tube: u32) {
+        self.keys.insert(key.to_lowercase(), tube);
+    }
+
+    pub fn unbind(&mut self, key: &str) -> bool {
+        self.keys.remove(&key.to_lowercase()).is_some()
+    }
+
+    /// The keys that play the tube with id `tube`, in order.
+    pub fn keys_for(&self, tube: u32) -> Vec<&str> {
+        self.keys
+            .iter()
+            .filter(|&(_, &id)| id == tube)
+            .map(|(key, _)| key.as_str())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_match_in_any_case() {
+        let mut keymap = Keymap::default();
+        assert_eq!(keymap.tube("a"), Some(1));
+        assert_eq!(keymap.tube("A"), Some(1));
+        keymap.bind("Q", 9);
+        assert_eq!(keymap.tube("q"), Some(9));
+        assert_eq!(keymap.keys_for(9), vec!["q"]);
+        assert!(keymap.unbind("q"));
+        assert_eq!(keymap.tube("Q"), None);
+        assert!(!keymap.unbind("Q"));
+    }
+
+    #[test]
+    fn keys_for_a_tube_are_in_order() {
+        let mut keymap = Keymap::default();
+        keymap.bind("z", 3);
+        keymap.bind("1", 3);
+        assert_eq!(keymap.keys_for(3), vec!["1", "d", "z"]);
+        assert!(keymap.keys_for(42).is_empty());
+    }
+
+    #[test]
+    fn rebinding_a_key_moves_it() {
+        let mut keymap = Keymap::default();
+        keymap.bind("S", 5);
+        assert_eq!(keymap.tube("s"), Some(5));
+        assert!(keymap.keys_for(2).is_empty());
+        assert_eq!(keymap.keys_for(5), vec!["g", "s"]);
+    }
+}
@@ -0,0 +1,267 @@
+use serde_json;
+
+use geometry::{Point, Vector2};
+use keymap::Keymap;
+use medium::Medium;
+use sequencer::Sequence;
+use synth::Envelope;
+use tuning::Tuning;
+use {DEFAULT_RADIUS, DEFAULT_ROLLOFF};
+
+/// Bumped whenever the document shape changes incompatibly.
+pub const VERSION: u32 = 1;
+
+/// A saved instrument: the grid, the settings and every tube on it.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Layout {
+    pub version: u32,
+    pub width: usize,
+    pub height: usize,
+    pub stop_selected: bool,
+    #[serde(default)]
+    pub medium: Medium,
+    #[serde(default)]
+    pub tuning: Tuning,
+    pub tubes: Vec<TubeLayout>,
+    #[serde(default)]
+    pub sequence: Sequence,
+    #[serde(default)]
+    pub keymap: Keymap,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TubeLayout {
+    /// Zero in layouts saved before tubes had ids; such tubes get a fresh
+    /// one.
+    #[serde(default)]
+    pub id: u32,
+    pub from: Point,
+    /// Unit vector from `from` toward the far end; older layouts only had
+    /// upright tubes.
+    #[serde(default = "Vector2::up")]
+    pub direction: Vector2,
+    pub length: f64,
+    #[serde(default = "default_radius")]
+    pub radius: f64,
+    #[serde(default = "default_rolloff")]
+    pub rolloff: f64,
+    #[serde(default = "default_mode")]
+    pub mode: u32,
+    #[serde(default)]
+    pub envelope: Envelope,
+    pub open: bool,
+    pub playing: bool,
+}
+
+impl TubeLayout {
+    /// Checks that the tube has a position, a direction and a size that
+    /// geometry and tuning can work with.
+    pub fn validate(&self) -> Result<(), String> {
+        let Vector2 { x, y } = self.direction;
+        if !x.is_finite() || !y.is_finite() || (x == 0.0 && y == 0.0) {
+            return Err(format!("tube {} has no direction", self.id));
+        }
+        if !self.from.x.is_finite() || !self.from.y.is_finite() {
+            return Err(format!("tube {} has no position", self.id));
+        }
+        if !self.length.is_finite() || self.length <= 0.0 {
+            return Err(format!("tube {} has length {}", self.id, self.length));
+        }
+        if !self.radius.is_finite() || self.radius <= 0.0 {
+            return Err(format!("tube {} has radius {}", self.id, self.radius));
+        }
+        Ok(())
+    }
+
+    /// A new, silent closed tube, yet to be given an id.
+    pub fn new(from: Point, direction: Vector2, length: f64) -> TubeLayout {
+        TubeLayout {
+            id: 0,
+            from,
+            direction,
+            length,
+            radius: DEFAULT_RADIUS,
+            rolloff: DEFAULT_ROLLOFF,
+            mode: 1,
+            envelope: Envelope::default(),
+            open: false,
+            playing: false,
+        }
+    }
+}
+
+fn default_radius() -> f64 {
+    DEFAULT_RADIUS
+}
+
+fn default_rolloff() -> f64 {
+    DEFAULT_ROLLOFF
+}
+
+fn default_mode() -> u32 {
+    1
+}
+
+impl Layout {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    pub fn from_json(json: &str) -> Result<Layout, String> {
+        let mut layout: Layout = serde_json::from_str(json).map_err(|e| e.to_string())?;
+        if layout.version > VERSION {
+            return Err(format!(
+                "layout version {} is newer than supported version {}",
+                layout.version, VERSION
+            ));
+        }
+        layout.medium.validate()?;
+        layout.tuning.validate()?;
+        for tube in &layout.tubes {
+            tube.validate()?;
+        }
+        if !(layout.sequence.tempo > 0.0 && layout.sequence.tempo.is_finite()) {
+            return Err(format!("sequence has tempo {}", layout.sequence.tempo));
+        }
+        layout.sequence.normalize();
+        Ok(layout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sequencer::NoteEvent;
+
+    fn layout(tube: TubeLayout) -> Layout {
+        Layout {
+            version: VERSION,
+            width: 10,
+            height: 10,
+            stop_selected: true,
+            medium: Medium::default(),
+            tuning: Tuning::default(),
+            tubes: vec![tube],
+            sequence: Sequence::default(),
+            keymap: Keymap::default(),
+        }
+    }
+
+    fn tube() -> TubeLayout {
+        TubeLayout::new(
+            Point { x: 20.0, y: 200.0 },
+            Vector2 { x: 0.6, y: -0.8 },
+            100.0,
+        )
+    }
+
+    #[test]
+    fn round_trips_through_json() {
+        let json = layout(tube()).to_json();
+        let loaded = Layout::from_json(&json).unwrap();
+        assert_eq!(loaded.tubes[0].direction, Vector2 { x: 0.6, y: -0.8 });
+        assert_eq!(loaded.tubes[0].length, 100.0);
+    }
+
+    #[test]
+    fn rejects_tubes_without_direction() {
+        let mut zero = tube();
+        zero.direction = Vector2 { x: 0.0, y: 0.0 };
+        assert!(Layout::from_json(&layout(zero).to_json()).is_err());
+        // JSON has no NaN, and serde_json refuses numbers too large for a
+        // double, so non-finite values can only be checked directly.
+        let json = layout(tube()).to_json().replace("-0.8", "-1e999");
+        assert!(Layout::from_json(&json).is_err());
+        for &(x, y) in &[
+            (::std::f64::NAN, 0.0),
+            (0.0, ::std::f64::INFINITY),
+            (::std::f64::NEG_INFINITY, 1.0),
+        ] {
+            let mut broken = tube();
+            broken.direction = Vector2 { x, y };
+            assert!(broken.validate().is_err(), "{:?}", broken.direction);
+        }
+    }
+
+    #[test]
+    fn rejects_tubes_without_position() {
+        let mut nowhere = tube();
+        nowhere.from = Point {
+            x: ::std::f64::NAN,
+            y: 0.0,
+        };
+        assert!(nowhere.validate().is_err());
+        nowhere.from = Point {
+            x: 0.0,
+            y: ::std::f64::INFINITY,
+        };
+        assert!(nowhere.validate().is_err());
+        assert!(tube().validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_tubes_without_size() {
+        let mut flat = tube();
+        flat.length = 0.0;
+        assert!(Layout::from_json(&layout(flat).to_json()).is_err());
+        let mut thin = tube();
+        thin.radius = -0.01;
+        assert!(Layout::from_json(&layout(thin).to_json()).is_err());
+        let mut endless = tube();
+        endless.length = ::std::f64::INFINITY;
+        assert!(endless.validate().is_err());
+        let mut vague = tube();
+        vague.radius = ::std::f64::NAN;
+        assert!(vague.validate().is_err());
+    }
+
+    #[test]
+    fn rejects_broken_tunings() {
+        let mut empty = layout(tube());
+        empty.tuning.ratios.clear();
+        assert!(Layout::from_json(&empty.to_json()).is_err());
+        let mut unmapped = layout(tube());
+        unmapped.tuning.mapping = Some(Vec::new());
+        assert!(Layout::from_json(&unmapped.to_json()).is_err());
+        let mut silent = layout(tube());
+        silent.tuning.reference_frequency = 0.0;
+        assert!(Layout::from_json(&silent.to_json()).is_err());
+    }
+
+    #[test]
+    fn sorts_loaded_sequences() {
+        let mut unsorted = layout(tube());
+        let note = |start: f64| NoteEvent {
+            tube: 1,
+            start,
+            duration: 1.0,
+            velocity: 1.0,
+        };
+        unsorted.sequence.events = vec![note(2.0), note(0.0), note(1.0)];
+        let loaded = Layout::from_json(&unsorted.to_json()).unwrap();
+        let starts = loaded
+            .sequence
+            .events
+            .iter()
+            .map(|e| e.start)
+            .collect::<Vec<_>>();
+        assert_eq!(starts, vec![0.0, 1.0, 2.0]);
+    }
+
+    #[test]
+    fn keymaps_survive_saving() {
+        let mut saved = layout(tube());
+        saved.keymap.bind("Z", 7);
+        saved.keymap.unbind("a");
+        let loaded = Layout::from_json(&saved.to_json()).unwrap();
+        assert_eq!(loaded.keymap, saved.keymap);
+        assert_eq!(loaded.keymap.tube("z"), Some(7));
+        assert_eq!(loaded.keymap.tube("a"), None);
+
+        // Layouts saved before keymaps get the home row.
+        let mut old = serde_json::to_value(&saved).unwrap();
+        old.as_object_mut().unwrap().remove("keymap");
+        let loaded = Layout::from_json(&old.to_string()).unwrap();
+        assert_eq!(loaded.keymap, Keymap::default());
+    }
+}
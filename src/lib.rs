@@ -1,19 +1,47 @@
 #![feature(vec_remove_item, proc_macro, wasm_custom_section, wasm_import_module)]
 #![allow(unused)]
 
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
 extern crate wasm_bindgen;
 
+use std::collections::{HashMap, VecDeque};
 use std::f64::consts::PI;
-use std::fmt;
 use std::mem;
-use std::ops::{Add, Mul};
 
 use wasm_bindgen::describe::WasmDescribe;
 use wasm_bindgen::prelude::*;
 
 const CELL_SIZE: usize = 20;
 
+mod geometry;
+mod history;
+mod keymap;
+mod layout;
+mod medium;
+mod midi;
 mod piano;
+mod sequencer;
+mod spectrum;
+mod surface;
+mod svg;
+mod synth;
+mod tuning;
+mod wav;
+
+use geometry::{between, Circle, Point, Segment, Vector2};
+use history::{Edit, History};
+use keymap::Keymap;
+use layout::{Layout, TubeLayout};
+use medium::{Gas, Medium};
+use midi::{Action, Controller, Performance};
+use sequencer::{NoteEvent, Playback, Recorder, Sequence};
+use surface::Surface;
+use svg::SvgSurface;
+use synth::{Envelope, OfflineSynth, Synth};
+use tuning::Tuning;
 
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
 struct Cell {
@@ -21,62 +49,6 @@ struct Cell {
     col: usize,
 }
 
-#[derive(PartialEq, Copy, Clone)]
-struct Point {
-    x: f64,
-    y: f64,
-}
-
-impl fmt::Debug for Point {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "({}, {})", self.x, self.y)
-    }
-}
-
-impl Point {
-    fn origin() -> Point {
-        Point { x: 0.0, y: 0.0 }
-    }
-
-    fn distance_to(self, o: Point) -> f64 {
-        ((self.x - o.x).powi(2) + (self.y - o.y).powi(2)).sqrt()
-    }
-
-    fn cross(self, o: Point) -> f64 {
-        self.x * o.y - o.x * self.y
-    }
-
-    fn rotate(self, angle: f64) -> Point {
-        let Point { x, y } = self;
-        Point {
-            x: x * angle.cos() - y * angle.sin(),
-            y: x * angle.sin() + y * angle.cos(),
-        }
-    }
-}
-
-impl Add<Vector2> for Point {
-    type Output = Point;
-
-    fn add(self, other: Vector2) -> Point {
-        Point {
-            x: (self.x + other.x),
-            y: (self.y + other.y),
-        }
-    }
-}
-
-impl Mul<f64> for Vector2 {
-    type Output = Vector2;
-
-    fn mul(self, other: f64) -> Vector2 {
-        Vector2 {
-            x: self.x * other,
-            y: self.y * other,
-        }
-    }
-}
-
 impl Cell {
     fn new(row: usize, col: usize) -> Cell {
         Cell { row, col }
@@ -104,351 +76,277 @@ impl Cell {
     }
 }
 
-#[derive(Debug, Copy, Clone, PartialEq)]
-struct Rectangle {
-    base_a: Point,
-    base_b: Point,
-    height: f64,
-}
-
-#[derive(Debug, Copy, Clone, PartialEq)]
-struct Segment {
-    from: Point,
-    to: Point,
-}
-
-impl Segment {
-    fn new(a: Point, b: Point) -> Self {
-        Segment { from: a, to: b }
-    }
-
-    fn midpoint(self) -> Point {
-        Point {
-            x: (self.from.x + self.to.x) / 2.0,
-            y: (self.from.y + self.to.y) / 2.0,
-        }
-    }
-
-    fn draw(self, ctx: &mut CanvasRenderingContext2D, color: &str) {
-        Circle::new(self.from, 4.0).draw(ctx, "grey");
-        Circle::new(self.to, 4.0).draw(ctx, "purple");
-        ctx.begin_path();
-        ctx.move_to(self.from.x as usize, self.from.y as usize);
-        ctx.line_to(self.to.x as usize, self.to.y as usize);
-        ctx.set_stroke_style(color);
-        ctx.set_line_width(3.0);
-        ctx.stroke();
-    }
-
-    fn to_rectangle(self, width: usize) -> Rectangle {
-        // XXX: unimplemented
-        Rectangle {
-            base_a: self.from,
-            base_b: self.to,
-            height: 20.0,
-        }
-    }
-
-    fn length(self) -> f64 {
-        self.from.distance_to(self.to)
-    }
-
-    fn contains(self, needle: Point) -> bool {
-        let t = Point {
-            x: self.to.x - self.from.x,
-            y: self.to.y - self.to.x,
-        };
-        let b = Point {
-            x: needle.x - self.from.x,
-            y: needle.y - self.from.y,
-        };
-        t.cross(b).abs() < ::std::f64::EPSILON
-    }
-
-    fn is_right_of_line(self, needle: Point) -> bool {
-        let t = Point {
-            x: self.to.x - self.from.x,
-            y: self.to.y - self.to.x,
-        };
-        let b = Point {
-            x: needle.x - self.from.x,
-            y: needle.y - self.from.y,
-        };
-        t.cross(b) < 0.0
-    }
+#[wasm_bindgen]
+pub struct Universe {
+    pub width: usize,
+    pub height: usize,
+    points: Vec<Cell>,
+    ctx: CanvasRenderingContext2D,
 
-    fn is_parallel(self, other: Self) -> bool {
-        self.from.y - self.to.y == other.from.y - other.to.y
-            && self.from.x - self.to.x == other.from.x - other.to.x
-    }
+    active: Option<Cell>,
+    active_pt: Option<Point>,
+    /// Where the pointer last hovered while a tube is being created.
+    hover: Option<Point>,
 
-    fn intersects(self, other: Self) -> bool {
-        let i = !self.is_parallel(other)
-            && (self.contains(other.from) || self.contains(other.to)
-                || (self.is_right_of_line(other.from) ^ self.is_right_of_line(other.to)));
-        log(&format!("{:?} intersects {:?} = {:?}", self, other, i));
-        i
-    }
-}
+    tubes: Vec<Tube>,
 
-/// Is p1 between p2 and p3?
-fn between(p1: f64, p2: f64, p3: f64) -> bool {
-    (p2 < p1 && p1 < p3) || (p3 < p1 && p1 < p2) || p1 == p2 || p1 == p3
+    audio: Audio,
+    stop_selected: bool,
+    medium: Medium,
+    tuning: Tuning,
+    history: History,
+    drag: Option<Drag>,
+    /// The largest tube id handed out so far.
+    next_id: u32,
+    sequence: Sequence,
+    playback: Option<Playback>,
+    recorder: Option<Recorder>,
+    /// Voices playing sequence notes, with the audio time they fall silent.
+    sounding: Vec<(AudioSource, f64)>,
+    controller: Controller,
+    /// The tube each note from the MIDI controller is playing.
+    midi_notes: HashMap<u8, u32>,
+    /// The controller's pitch bend, in cents.
+    bend: f64,
+    keymap: Keymap,
+    /// Whether bound keys play tubes instead of editing them.
+    performing: bool,
+    /// Whether the output's waveform and spectrum are drawn.
+    analysing: bool,
 }
 
-#[derive(Copy, Clone)]
-struct Vector2 {
-    x: f64,
-    y: f64,
+/// A tube being dragged by the pointer, with what it looked like when the
+/// drag began.
+enum Drag {
+    Move {
+        index: usize,
+        grab: Vector2,
+        origin: Point,
+    },
+    Resize {
+        index: usize,
+        origin: f64,
+    },
+    /// A selection box dragged over empty space.
+    Select {
+        from: Point,
+        to: Point,
+        extend: bool,
+    },
 }
 
-impl fmt::Debug for Vector2 {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "<{}, {}>", self.x, self.y)
-    }
+struct Tube {
+    /// Identifies the tube in sequences; unlike its index, it never changes.
+    id: u32,
+    length: f64,
+    radius: f64,
+    rolloff: f64,
+    /// Which harmonic of the tube sounds: odd only for a closed tube.
+    mode: u32,
+    envelope: Envelope,
+    /// The closed end, or either end of an open tube.
+    from: Point,
+    /// Unit vector from `from` along the tube.
+    direction: Vector2,
+    selected: bool,
+    source: AudioSource,
+    open: bool,
 }
 
-impl Vector2 {
-    fn to_point(pt: Point) -> Vector2 {
-        Vector2::from_segment(Segment::new(pt, Point { x: 0.0, y: 0.0 }))
-    }
-
-    fn i() -> Vector2 {
-        Vector2 { x: 1.0, y: 0.0 }
-    }
-
-    fn j() -> Vector2 {
-        Vector2 { x: 0.0, y: 1.0 }
-    }
-
-    fn angle_to(self, v: Vector2) -> f64 {
-        (self.dot(v) / (self.magnitude() * v.magnitude())).acos()
-    }
+const PIXELS_PER_METER: f64 = 1300.0;
+/// How close, in pixels, a pointer must be to a tube's end to resize it.
+const END_GRAB_DISTANCE: f64 = 10.0;
+/// How far, in pixels, the pointer must travel from empty space before
+/// pressing it selects with a box rather than clicks.
+const DRAG_THRESHOLD: f64 = 4.0;
+/// Shortest tube that can be made by resizing, in pixels.
+const MIN_LENGTH: f64 = 10.0;
+/// Inner radius of a new tube, in meters.
+const DEFAULT_RADIUS: f64 = 10.0 / PIXELS_PER_METER;
+/// How far the standing wave reaches past each open end, in radii.
+const END_CORRECTION: f64 = 0.6;
+/// How steeply the harmonics of a new tube fade, as the exponent in 1/n^x.
+const DEFAULT_ROLLOFF: f64 = 1.0;
+/// Number of harmonics synthesized for each tube.
+const HARMONICS: usize = 16;
+/// Spacing, in pixels, of the spots new tubes are stood on.
+const SLOT: f64 = CELL_SIZE as f64 * 2.5;
+/// How far, in cents, a tube may be from a note's pitch and still play it.
+const MATCH_CENTS: f64 = 15.0;
+/// Sample rate of audio exported as WAV files.
+const EXPORT_SAMPLE_RATE: f64 = 44100.0;
+/// Loudest sample in exported audio, leaving headroom below full scale.
+const EXPORT_PEAK: f32 = 0.9;
+/// Longest time, in seconds, the playing tubes can be exported for.
+const MAX_EXPORT_SECONDS: f64 = 60.0;
+/// Longest time, in seconds, of an exported sequence; the rest is cut off.
+const MAX_SEQUENCE_SECONDS: f64 = 600.0;
+
+/// How far ahead of the audio clock, in seconds, sequence notes are
+/// scheduled.
+const SCHEDULE_AHEAD: f64 = 0.1;
+
+/// Samples of output analysed at a time; a power of two.
+const ANALYSIS_SIZE: usize = 4096;
+/// Size, in pixels, of the analysis panel's waveform and spectrum.
+const ANALYSIS_WIDTH: f64 = 400.0;
+const WAVEFORM_HEIGHT: f64 = 80.0;
+const SPECTRUM_HEIGHT: f64 = 160.0;
+/// Lowest frequency, in Hz, and level, in decibels, the spectrum shows.
+const SPECTRUM_LOW: f64 = 20.0;
+const SPECTRUM_FLOOR: f64 = -90.0;
+/// How many partials are labelled, and how loud they must be.
+const LABELLED_PEAKS: usize = 6;
+const PEAK_FLOOR: f64 = 0.001;
 
-    fn anchor_at(self, p: Point) -> Segment {
-        Segment {
-            from: p,
-            to: p + self,
+impl Tube {
+    /// The quarter (closed) or half (open) wavelength a tube spans, and how
+    /// many of its ends are open.
+    fn resonance(open: bool) -> (f64, f64) {
+        if open {
+            (2.0, 2.0)
+        } else {
+            (4.0, 1.0)
         }
     }
 
-    fn dot(self, o: Vector2) -> f64 {
-        self.x * o.x + self.y * o.y
-    }
-
-    fn project_onto(self, b: Vector2) -> Vector2 {
-        let a = self;
-        //log(&format!(
-        //    "{:?} onto {:?} = {:?}",
-        //    a,
-        //    b,
-        //    b * (a.dot(b) as f64 / b.dot(b) as f64) as f64
-        //));
-        b * (a.dot(b) as f64 / b.dot(b) as f64) as f64
+    /// Length in pixels of a tube whose fundamental is `frequency` when
+    /// sound travels at `speed` m/s. A pitch too high for the radius, whose
+    /// end corrections alone would overshoot it, gets the shortest tube.
+    fn length(frequency: f64, radius: f64, open: bool, speed: f64) -> f64 {
+        let (divisor, open_ends) = Tube::resonance(open);
+        let length = (speed / (divisor * frequency) - open_ends * END_CORRECTION * radius)
+            * PIXELS_PER_METER;
+        length.max(MIN_LENGTH)
     }
 
-    fn from_segment(s: Segment) -> Vector2 {
-        Vector2 {
-            x: (s.from.x as f64) - (s.to.x as f64),
-            y: (s.from.y as f64) - (s.to.y as f64),
-        }
+    /// Fundamental frequency of a tube `length` pixels long.
+    fn frequency_for(length: f64, radius: f64, open: bool, speed: f64) -> f64 {
+        let (divisor, open_ends) = Tube::resonance(open);
+        speed / (divisor * (length / PIXELS_PER_METER + open_ends * END_CORRECTION * radius))
     }
 
-    fn orthogonal_cw(self) -> Vector2 {
-        Vector2 {
-            x: self.y,
-            y: -self.x,
-        }
+    fn frequency(&self, speed: f64) -> f64 {
+        self.mode as f64 * Tube::frequency_for(self.length, self.radius, self.open, speed)
     }
 
-    fn orthogonal_ccw(self) -> Vector2 {
-        Vector2 {
-            x: -self.y,
-            y: self.x,
+    /// The neighbouring mode above or below the current one that this tube
+    /// can sustain.
+    fn step_mode(&self, up: bool) -> u32 {
+        let step = if self.open { 1 } else { 2 };
+        if up {
+            (self.mode + step).min(HARMONICS as u32)
+        } else if self.mode > step {
+            self.mode - step
+        } else {
+            1
         }
     }
 
-    fn magnitude(self) -> f64 {
-        (self.x.powi(2) + self.y.powi(2)).sqrt()
-    }
-
-    fn with_length(self, length: f64) -> Vector2 {
-        let scale = length as f64 / self.magnitude();
-        let x = self.x as f64;
-        let y = self.y as f64;
-        Vector2 {
-            x: (x * scale),
-            y: (y * scale),
+    fn set_mode<S: Synth>(&mut self, audio: &mut Audio<S>, mode: u32, speed: f64) {
+        // Loaded layouts may ask for any mode, but only the harmonics that
+        // are synthesized can sound.
+        self.mode = mode.max(1).min(HARMONICS as u32);
+        if !self.open && self.mode % 2 == 0 {
+            self.mode -= 1;
         }
+        self.adjust_frequency(audio, speed);
     }
-}
-
-impl Rectangle {
-    fn contains(&self, needle: Point) -> bool {
-        let [p1, p2, p3, p4] = {
-            let v = Vector2::from_segment(Segment {
-                from: self.base_a,
-                to: self.base_b,
-            });
-            let v1 = v.orthogonal_ccw().with_length(self.height / 2.0);
-            let v2 = v.orthogonal_cw().with_length(self.height / 2.0);
-            let p1 = self.base_a + v1;
-            let p2 = self.base_a + v2;
-            let p3 = self.base_b + v2;
-            let p4 = self.base_b + v1;
-            [p1, p2, p3, p4]
-        };
 
-        let side = if p1.y < p2.y {
-            Segment::new(p1, p2)
+    /// Wave number (radians per pixel) and phase of the displacement
+    /// standing wave, measured from the tube's base.
+    fn standing_wave(&self) -> (f64, f64) {
+        if self.open {
+            (self.mode as f64 * PI / self.length, PI / 2.0)
         } else {
-            Segment::new(p2, p1)
-        };
-        let off_x = Vector2::i().angle_to(Vector2::from_segment(side));
-        let p1 = p1.rotate(off_x);
-        let p2 = p2.rotate(off_x);
-        let p3 = p3.rotate(off_x);
-        let p4 = p4.rotate(off_x);
-        let np = needle.rotate(off_x);
-
-        if between(np.x, p1.x, p3.x) && between(np.y, p1.y, p3.y) {
-            return true;
+            (self.mode as f64 * PI / (2.0 * self.length), 0.0)
         }
-
-        false
-    }
-
-    fn center(&self) -> Point {
-        let v = Vector2::from_segment(Segment {
-            from: self.base_a,
-            to: self.base_b,
-        });
-        let v1 = v.orthogonal_ccw().with_length(self.height / 2.0);
-        let v2 = v.orthogonal_cw().with_length(self.height / 2.0);
-        let p1 = self.base_a + v1;
-        let p3 = self.base_b + v2;
-        Segment::new(p1, p3).midpoint()
-    }
-
-    fn draw(&self, ctx: &mut CanvasRenderingContext2D, color: &str) {
-        let v = Vector2::from_segment(Segment {
-            from: self.base_a,
-            to: self.base_b,
-        });
-        let v1 = v.orthogonal_ccw().with_length(self.height / 2.0);
-        let v2 = v.orthogonal_cw().with_length(self.height / 2.0);
-        let p1 = self.base_a + v1;
-        let p2 = self.base_a + v2;
-        let p3 = self.base_b + v2;
-        let p4 = self.base_b + v1;
-
-        ctx.set_line_width(4.0);
-        ctx.begin_path();
-        ctx.move_to(p1.x as usize, p1.y as usize);
-        ctx.line_to(p2.x as usize, p2.y as usize);
-        ctx.line_to(p3.x as usize, p3.y as usize);
-        ctx.move_to(p4.x as usize, p4.y as usize);
-        ctx.line_to(p1.x as usize, p1.y as usize);
-        ctx.set_stroke_style(color);
-        ctx.stroke();
     }
-}
-
-struct Circle {
-    x: f64,
-    y: f64,
-    radius: f64,
-}
-
-impl Circle {
-    fn new(Point { x, y }: Point, radius: f64) -> Circle {
-        Circle { x, y, radius }
-    }
-
-    fn draw(&self, ctx: &mut CanvasRenderingContext2D, color: &str) {
-        ctx.begin_path();
-        ctx.move_to(self.x as usize, self.y as usize);
-        ctx.arc(
-            self.x as usize,
-            self.y as usize,
-            self.radius,
-            0.0,
-            PI * 2.0,
-            false,
-        );
-        ctx.set_fill_style(color);
-        ctx.fill();
-    }
-}
-
-#[wasm_bindgen]
-pub struct Universe {
-    pub width: usize,
-    pub height: usize,
-    points: Vec<Cell>,
-    ctx: CanvasRenderingContext2D,
-
-    active: Option<Cell>,
-    active_pt: Option<Point>,
-
-    tubes: Vec<Tube>,
-
-    audio: Audio,
-    stop_selected: bool,
-}
-
-struct Tube {
-    length: f64,
-    from: Point,
-    selected: bool,
-    source: AudioSource,
-    open: bool,
-}
-
-const SPEED: f64 = 343.0;
-const PIXELS_PER_METER: f64 = 1300.0;
 
-impl Tube {
-    fn length(frequency: f64) -> f64 {
-        SPEED / (4.0 * frequency) * (PIXELS_PER_METER)
+    fn width(&self) -> f64 {
+        2.0 * self.radius * PIXELS_PER_METER
     }
 
     fn segment(&self) -> Segment {
-        Segment::new(
-            self.from,
-            Point {
-                x: self.from.x,
-                y: self.from.y - self.length,
-            },
-        )
+        Segment::new(self.from, self.from + self.direction * self.length)
     }
 
     fn contains(&self, p: Point) -> bool {
-        self.segment().to_rectangle(0).contains(p)
+        self.segment().to_rectangle(self.width()).contains(p)
     }
 
-    fn new(audio: &Audio, from: Point, length: f64) -> Tube {
-        // v = 343 m/s
-        // L = length
+    /// Whether `p` is close enough to the tube's far end to grab it.
+    fn grabs_end(&self, p: Point) -> bool {
+        p.distance_to(self.segment().to) <= END_GRAB_DISTANCE
+    }
+
+    fn new<S: Synth>(
+        audio: &mut Audio<S>,
+        from: Point,
+        direction: Vector2,
+        length: f64,
+        speed: f64,
+    ) -> Tube {
+        // v = speed of sound in the medium, 343 m/s in air at 20°C
+        // L = length, r = radius
         // Fundamental:
-        // wavelength = 4 * length
+        // wavelength = 4 * (L + 0.6r)
         // v = wavelength * freq
-        // freq = 343 / wavelength
-        let mut source = audio.get_source(SPEED / (4.0 * length / PIXELS_PER_METER));
-        Tube {
+        // freq = v / wavelength
+        let mut source =
+            audio.get_source(Tube::frequency_for(length, DEFAULT_RADIUS, false, speed));
+        let tube = Tube {
+            id: 0,
             length: length,
+            radius: DEFAULT_RADIUS,
+            rolloff: DEFAULT_ROLLOFF,
+            mode: 1,
+            envelope: Envelope::default(),
             from,
+            direction: direction.with_length(1.0),
             selected: false,
             source: source,
             open: false,
+        };
+        tube.adjust_timbre(audio);
+        tube
+    }
+
+    fn from_layout<S: Synth>(audio: &mut Audio<S>, layout: &TubeLayout, speed: f64) -> Tube {
+        let mut tube = Tube::new(audio, layout.from, layout.direction, layout.length, speed);
+        tube.id = layout.id;
+        tube.radius = layout.radius;
+        tube.rolloff = layout.rolloff;
+        tube.open = layout.open;
+        tube.set_mode(audio, layout.mode, speed);
+        tube.set_envelope(audio, layout.envelope);
+        tube.adjust_timbre(audio);
+        if layout.playing {
+            tube.source.start(audio);
+        }
+        tube
+    }
+
+    fn to_layout(&self) -> TubeLayout {
+        TubeLayout {
+            id: self.id,
+            from: self.from,
+            direction: self.direction,
+            length: self.length,
+            radius: self.radius,
+            rolloff: self.rolloff,
+            mode: self.mode,
+            envelope: self.envelope,
+            open: self.open,
+            playing: self.source.playing,
         }
     }
 
-    fn set_selected(&mut self, audio: &Audio, stop_selected: bool, s: bool) {
+    fn set_selected<S: Synth>(&mut self, audio: &mut Audio<S>, stop_selected: bool, s: bool) {
         if s {
             self.selected = true;
             if stop_selected {
-                self.source.start();
+                self.source.start(audio);
             }
         } else {
             self.selected = false;
@@ -458,25 +356,52 @@ impl Tube {
         }
     }
 
-    fn adjust_frequency(&mut self) {
-        if self.open {
-            self.source
-                .set_frequency(SPEED / (2.0 * self.length / PIXELS_PER_METER));
-        } else {
-            self.source
-                .set_frequency(SPEED / (4.0 * self.length / PIXELS_PER_METER));
-        }
+    fn adjust_frequency<S: Synth>(&mut self, audio: &mut Audio<S>, speed: f64) {
+        let frequency = self.frequency(speed);
+        self.source.set_frequency(audio, frequency);
+    }
+
+    fn adjust_timbre<S: Synth>(&self, audio: &mut Audio<S>) {
+        let amplitudes = synth::harmonics(self.open, self.rolloff, HARMONICS);
+        audio.synth.set_harmonics(self.source.c, &amplitudes);
+    }
+
+    /// A new source that sounds like this tube, to play it independently.
+    fn voice<S: Synth>(&self, audio: &mut Audio<S>, speed: f64) -> AudioSource {
+        let source = audio.get_source(self.frequency(speed));
+        let amplitudes = synth::harmonics(self.open, self.rolloff, HARMONICS);
+        audio.synth.set_harmonics(source.c, &amplitudes);
+        audio.synth.set_envelope(source.c, self.envelope);
+        source
+    }
+
+    fn set_envelope<S: Synth>(&mut self, audio: &mut Audio<S>, envelope: Envelope) {
+        self.envelope = envelope.clamped();
+        audio.synth.set_envelope(self.source.c, self.envelope);
+    }
+
+    /// The length at which the tube's current mode sounds at `frequency`.
+    fn tuned_length(&self, frequency: f64, speed: f64) -> f64 {
+        let fundamental = frequency / self.mode as f64;
+        Tube::length(fundamental, self.radius, self.open, speed)
+    }
+
+    fn set_open<S: Synth>(&mut self, audio: &mut Audio<S>, open: bool, speed: f64) {
+        self.open = open;
+        let mode = self.mode;
+        self.set_mode(audio, mode, speed);
+        self.adjust_timbre(audio);
     }
 
-    fn draw(&self, ctx: &mut CanvasRenderingContext2D) {
+    fn draw<S: Surface>(&self, ctx: &mut S) {
         let v = Vector2::from_segment(self.segment());
         let Segment {
             from: base_a,
             to: base_b,
         } = self.segment();
-        const WIDTH: f64 = 20.0;
-        let v1 = v.orthogonal_ccw().with_length(WIDTH / 2.0);
-        let v2 = v.orthogonal_cw().with_length(WIDTH / 2.0);
+        let width = self.width();
+        let v1 = v.orthogonal_ccw().with_length(width / 2.0);
+        let v2 = v.orthogonal_cw().with_length(width / 2.0);
         let p1 = base_a + v1;
         let p2 = base_a + v2;
         let p3 = base_b + v2;
@@ -484,16 +409,16 @@ impl Tube {
 
         ctx.set_line_width(4.0);
         ctx.begin_path();
-        ctx.move_to(p1.x as usize, p1.y as usize);
+        ctx.move_to(p1.x, p1.y);
         if self.open {
-            ctx.move_to(p2.x as usize, p2.y as usize);
+            ctx.move_to(p2.x, p2.y);
         } else {
-            ctx.line_to(p2.x as usize, p2.y as usize);
+            ctx.line_to(p2.x, p2.y);
         }
-        ctx.line_to(p3.x as usize, p3.y as usize);
-        // skip side because open on top always
-        ctx.move_to(p4.x as usize, p4.y as usize);
-        ctx.line_to(p1.x as usize, p1.y as usize);
+        ctx.line_to(p3.x, p3.y);
+        // skip the far end, which is always open
+        ctx.move_to(p4.x, p4.y);
+        ctx.line_to(p1.x, p1.y);
         ctx.set_stroke_style(if self.selected {
             "#f0f"
         } else if self.source.playing {
@@ -503,129 +428,543 @@ impl Tube {
         });
         ctx.stroke();
 
+        let axis = Vector2::from_segment(Segment::new(base_b, base_a)).with_length(1.0);
+        let normal = axis.orthogonal_cw();
+        let (k, phase) = self.standing_wave();
+        let amplitude = width / 2.0 - 4.0;
         for &dir in [-1.0, 1.0].iter() {
             ctx.begin_path();
-            ctx.move_to(base_a.x as usize, base_a.y as usize);
-            let mut y = base_a.y;
-            let divisor = if self.open { 2.0 } else { 4.0 };
-            while y > base_b.y {
-                y -= 0.5;
-                let i = y - base_a.y;
-                let x = base_b.x
-                    + dir * (WIDTH / 2.0 - 4.0) * (i * 2.0 * PI / (divisor * v.magnitude())).sin();
-                ctx.line_to_float(x, y);
+            ctx.move_to(base_a.x, base_a.y);
+            let mut x = 0.0;
+            while x < self.length {
+                x = (x + 0.5).min(self.length);
+                let p = base_a + axis * x + normal * (dir * amplitude * (k * x + phase).sin());
+                ctx.line_to(p.x, p.y);
             }
             ctx.set_line_width(2.0);
             ctx.set_stroke_style("#94b4dd");
             ctx.stroke();
         }
+
+        // Displacement nodes sit where the wave's phase is a multiple of pi,
+        // antinodes halfway between.
+        let mut m = 0;
+        loop {
+            let x = (m as f64 * PI / 2.0 - phase) / k;
+            if x > self.length + 0.01 {
+                break;
+            }
+            if x >= -0.01 {
+                let color = if m % 2 == 0 { "#555" } else { "#e44" };
+                Circle::new(base_a + axis * x, 3.0).draw(ctx, color);
+            }
+            m += 1;
+        }
+    }
+}
+
+/// How many idle oscillators are kept running silently for reuse.
+const POOL_SIZE: usize = 32;
+
+/// A started oscillator feeding the master bus through its own gain. The
+/// oscillator stops and both nodes leave the graph when this is dropped.
+struct WebNodes {
+    source: OscillatorNode,
+    intermediate: GainNode,
+}
+
+impl Drop for WebNodes {
+    fn drop(&mut self) {
+        self.source.stop();
+        self.source.disconnect();
+        self.intermediate.disconnect();
+    }
+}
+
+/// A voice's settings, and its nodes while it is sounding or releasing.
+struct WebVoice {
+    frequency: f64,
+    amplitudes: Vec<f64>,
+    envelope: Envelope,
+    nodes: Option<WebNodes>,
+    /// Audio time at which the voice falls silent and its nodes can go back
+    /// to the pool; infinite while it sustains.
+    silent: f64,
+}
+
+impl WebVoice {
+    /// Ramps the gain through `ramps`, as (target, seconds), starting `now`
+    /// from wherever it is, and returns when the last ramp ends.
+    fn ramp(&self, ramps: &[(f64, f64)], now: f64) -> f64 {
+        let mut at = now;
+        if let Some(ref nodes) = self.nodes {
+            let gain = nodes.intermediate.gain();
+            gain.cancel_scheduled_values(now);
+            gain.set_value_at_time(gain.value(), now);
+            for &(target, seconds) in ramps {
+                at += seconds;
+                gain.linear_ramp_to(target, at);
+            }
+        }
+        at
     }
 }
 
-struct Audio {
+/// Options for `createPeriodicWave`.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct WaveConstraints {
+    disable_normalization: bool,
+}
+
+/// A wave of the given harmonic amplitudes. They are already scaled by
+/// `synth::harmonics`, so the browser is told not to rescale them, keeping
+/// the level the same as `OfflineSynth`'s.
+fn periodic_wave(ac: &AudioContext, amplitudes: &[f64]) -> PeriodicWave {
+    let real = vec![0.0; amplitudes.len()];
+    let imag = amplitudes.iter().map(|&a| a as f32).collect::<Vec<_>>();
+    let constraints = JsValue::from_serde(&WaveConstraints {
+        disable_normalization: true,
+    })
+    .unwrap();
+    ac.create_periodic_wave(&real, &imag, &constraints)
+}
+
+struct WebAudio {
     ac: AudioContext,
     master: GainNode,
+    /// Taps the output for the analysis panel.
+    analyser: AnalyserNode,
+    voices: HashMap<usize, WebVoice>,
+    /// Nodes of silent and stopped voices, oldest first, with the audio time
+    /// their release ends and they can be reused.
+    pool: VecDeque<(f64, WebNodes)>,
 }
 
-impl Audio {
+impl WebAudio {
     fn new(ac: AudioContext) -> Self {
         let master = ac.create_gain();
         master.connect(&ac.destination());
-        Audio { ac, master }
+        let analyser = ac.create_analyser();
+        analyser.set_fft_size(ANALYSIS_SIZE as u32);
+        master.connect_to_analyser(&analyser);
+        WebAudio {
+            ac,
+            master,
+            analyser,
+            voices: HashMap::new(),
+            pool: VecDeque::new(),
+        }
+    }
+
+    /// The last `ANALYSIS_SIZE` samples of output.
+    fn waveform(&self) -> Vec<f32> {
+        let mut samples = vec![0.0; ANALYSIS_SIZE];
+        self.analyser.get_float_time_domain_data(&mut samples);
+        samples
+    }
+
+    /// Gives the voice nodes to sound with, if it has none. Returns whether
+    /// the voice exists.
+    fn attach(&mut self, id: usize) -> bool {
+        let needs_nodes = match self.voices.get(&id) {
+            Some(voice) => voice.nodes.is_none(),
+            None => return false,
+        };
+        if needs_nodes {
+            let nodes = self.take_nodes();
+            let voice = self.voices.get_mut(&id).unwrap();
+            nodes.source.frequency().set_value(voice.frequency);
+            nodes
+                .source
+                .set_periodic_wave(&periodic_wave(&self.ac, &voice.amplitudes));
+            voice.nodes = Some(nodes);
+        }
+        true
+    }
+
+    /// Moves the nodes of voices silent by `now` to the pool, and drops the
+    /// oldest idle nodes beyond its size, but never ones still releasing.
+    fn reclaim(&mut self, now: f64) {
+        for voice in self.voices.values_mut() {
+            if voice.silent <= now {
+                if let Some(nodes) = voice.nodes.take() {
+                    self.pool.push_back((voice.silent, nodes));
+                }
+            }
+        }
+        while self.pool.len() > POOL_SIZE {
+            match self.pool.iter().position(|&(free, _)| free <= now) {
+                Some(i) => {
+                    self.pool.remove(i);
+                }
+                None => break,
+            }
+        }
     }
 
-    fn get_source(&self, frequency: f64) -> AudioSource {
+    /// The longest idle nodes from the pool whose release has ended, or new
+    /// ones.
+    fn take_nodes(&mut self) -> WebNodes {
+        let now = self.ac.current_time();
+        self.reclaim(now);
+        if let Some(i) = self.pool.iter().position(|&(free, _)| free <= now) {
+            return self.pool.remove(i).unwrap().1;
+        }
         let intermediate = self.ac.create_gain();
+        intermediate.gain().set_value(0.0);
         intermediate.connect_to_gain(&self.master);
-        let osc = self.ac.create_oscillator();
-        osc.connect(&intermediate);
-        osc.frequency().set_value(frequency);
-        AudioSource {
-            c: AS_COUNT.fetch_add(1, ::std::sync::atomic::Ordering::SeqCst),
-            source: osc,
-            playing: false,
-            frequency: frequency,
+        let source = self.ac.create_oscillator();
+        source.connect(&intermediate);
+        source.start();
+        WebNodes {
+            source,
             intermediate,
-            played: false,
         }
     }
 }
 
-use std::sync::atomic::AtomicUsize;
-static AS_COUNT: AtomicUsize = AtomicUsize::new(0);
+impl Synth for WebAudio {
+    fn add_voice(&mut self, id: usize, frequency: f64) {
+        self.voices.insert(
+            id,
+            WebVoice {
+                frequency,
+                amplitudes: vec![0.0, 1.0],
+                envelope: Envelope::default(),
+                nodes: None,
+                silent: 0.0,
+            },
+        );
+    }
 
-struct AudioSource {
-    c: usize,
-    frequency: f64,
-    source: OscillatorNode,
-    intermediate: GainNode,
-    playing: bool,
-    played: bool,
-}
+    fn start(&mut self, id: usize, velocity: f64) {
+        let now = self.ac.current_time();
+        if self.attach(id) {
+            let voice = self.voices.get_mut(&id).unwrap();
+            voice.ramp(&voice.envelope.on(velocity), now);
+            voice.silent = ::std::f64::INFINITY;
+        }
+    }
 
-impl AudioSource {
-    fn start(&mut self) {
-        if !self.playing {
-            self.playing = true;
-            if !self.played {
-                self.played = true;
-                self.source.start();
-            } else {
-                self.intermediate.gain().set_value(1.0);
+    /// Releases the voice. It keeps its nodes until the release has ended,
+    /// so starting it again in the meantime rises from the level it has
+    /// fallen to, as with `OfflineSynth`.
+    fn pause(&mut self, id: usize) {
+        let now = self.ac.current_time();
+        if let Some(voice) = self.voices.get_mut(&id) {
+            if voice.nodes.is_some() {
+                voice.silent = voice.ramp(&[voice.envelope.off()], now);
             }
         }
+        self.reclaim(now);
     }
 
-    fn stop(mut self) {
-        if self.playing {
-            self.playing = false;
-            self.source.stop();
+    /// Releases the voice and forgets it, leaving its nodes in the pool to
+    /// finish the release.
+    fn stop(&mut self, id: usize) {
+        self.pause(id);
+        if let Some(mut voice) = self.voices.remove(&id) {
+            if let Some(nodes) = voice.nodes.take() {
+                self.pool.push_back((voice.silent, nodes));
+            }
         }
     }
 
-    fn set_frequency(&mut self, f: f64) {
-        self.frequency = f;
-        self.source.frequency().set_value(f);
+    fn set_frequency(&mut self, id: usize, frequency: f64) {
+        if let Some(voice) = self.voices.get_mut(&id) {
+            voice.frequency = frequency;
+            if let Some(ref nodes) = voice.nodes {
+                nodes.source.frequency().set_value(frequency);
+            }
+        }
     }
 
-    fn pause(&mut self, audio: &Audio) {
-        if self.playing {
-            self.playing = false;
-            for i in 0..=10000 {
-                self.intermediate
-                    .gain()
-                    .set_value(1.0 - 1.0 / 10000.0 * i as f64);
+    fn set_harmonics(&mut self, id: usize, amplitudes: &[f64]) {
+        if let Some(voice) = self.voices.get_mut(&id) {
+            voice.amplitudes = amplitudes.to_vec();
+            if let Some(ref nodes) = voice.nodes {
+                nodes
+                    .source
+                    .set_periodic_wave(&periodic_wave(&self.ac, amplitudes));
             }
         }
     }
-}
 
-#[wasm_bindgen]
-impl Universe {
-    pub fn new(ac: AudioContext, ctx: CanvasRenderingContext2D) -> Self {
-        let audio = Audio::new(ac);
-        let frequencies = [
-            261.626, 293.665, 329.628, 349.228, 391.995, 440.000, 493.883, 523.251,
-        ];
+    fn set_envelope(&mut self, id: usize, envelope: Envelope) {
+        if let Some(voice) = self.voices.get_mut(&id) {
+            voice.envelope = envelope;
+        }
+    }
 
-        Universe {
-            height: 60,
-            width: 60,
-            points: Vec::new(),
-            ctx,
-            active: None,
-            active_pt: None,
-            tubes: frequencies
+    fn current_time(&self) -> f64 {
+        self.ac.current_time()
+    }
+
+    fn schedule_note(&mut self, id: usize, at: f64, duration: f64, velocity: f64) {
+        let now = self.ac.current_time();
+        if self.attach(id) {
+            let voice = self.voices.get_mut(&id).unwrap();
+            let mut ramps = vec![(0.0, (at - now).max(0.0))];
+            ramps.extend(voice.envelope.note(velocity, duration));
+            voice.silent = voice.ramp(&ramps, now);
+        }
+    }
+}
+
+/// Schedules the notes of `sequence` due before audio time `until`, each on
+/// a fresh voice of its tube. The voices go on `sounding` with the time they
+/// fall silent.
+fn schedule_notes<S: Synth>(
+    audio: &mut Audio<S>,
+    tubes: &[Tube],
+    sequence: &Sequence,
+    playback: &mut Playback,
+    until: f64,
+    speed: f64,
+    sounding: &mut Vec<(AudioSource, f64)>,
+) {
+    for event in playback.due(sequence, until) {
+        let tube = match tubes.iter().find(|t| t.id == event.tube) {
+            Some(tube) => tube,
+            None => continue,
+        };
+        let source = tube.voice(audio, speed);
+        let at = playback.time(event.start);
+        let duration = playback.time(event.end()) - at;
+        audio
+            .synth
+            .schedule_note(source.c, at, duration, event.velocity);
+        sounding.push((source, at + duration + tube.envelope.release));
+    }
+}
+
+/// Frees the voices on `sounding` that are silent by audio time `now`.
+fn release_finished<S: Synth>(
+    audio: &mut Audio<S>,
+    sounding: &mut Vec<(AudioSource, f64)>,
+    now: f64,
+) {
+    let (done, rest): (Vec<_>, Vec<_>) = sounding.drain(..).partition(|&(_, silent)| silent <= now);
+    *sounding = rest;
+    for (source, _) in done {
+        source.stop(audio);
+    }
+}
+
+/// Renders the playing `tubes` for `seconds`, then releases them and
+/// renders until the longest release has ended.
+fn render_tubes(tubes: &[Tube], speed: f64, seconds: f64) -> Vec<f32> {
+    let mut audio = Audio::new(OfflineSynth::new(EXPORT_SAMPLE_RATE));
+    let playing = tubes
+        .iter()
+        .filter(|t| t.source.playing)
+        .collect::<Vec<_>>();
+    let mut sources = playing
+        .iter()
+        .map(|t| t.voice(&mut audio, speed))
+        .collect::<Vec<_>>();
+    for source in &mut sources {
+        source.start(&mut audio);
+    }
+    let mut samples = audio.synth.render(frames(seconds));
+    for source in sources {
+        source.stop(&mut audio);
+    }
+    let release = playing
+        .iter()
+        .map(|t| t.envelope.release)
+        .fold(0.0, f64::max);
+    samples.extend(audio.synth.render(frames(release)));
+    samples
+}
+
+/// Renders `sequence` played on `tubes` from its start until its last note
+/// has been released, or for `seconds` at most, scheduling notes just as
+/// `Universe::tick` does.
+fn render_sequence(tubes: &[Tube], sequence: &Sequence, speed: f64, seconds: f64) -> Vec<f32> {
+    let limit = frames(seconds);
+    let mut audio = Audio::new(OfflineSynth::new(EXPORT_SAMPLE_RATE));
+    let mut playback = Playback::new(sequence, 0.0);
+    let mut sounding = Vec::new();
+    let mut samples = Vec::new();
+    loop {
+        let now = audio.synth.current_time();
+        schedule_notes(
+            &mut audio,
+            tubes,
+            sequence,
+            &mut playback,
+            now + SCHEDULE_AHEAD,
+            speed,
+            &mut sounding,
+        );
+        release_finished(&mut audio, &mut sounding, now);
+        if playback.is_finished(sequence) && sounding.is_empty() || samples.len() >= limit {
+            break;
+        }
+        samples.extend(audio.synth.render(frames(SCHEDULE_AHEAD)));
+    }
+    samples.truncate(limit);
+    samples
+}
+
+/// Where to stand a new upright tube on a `width` by `height` pixel canvas:
+/// the lowest, then leftmost, point of a grid spaced `SLOT` apart that is
+/// clear of every tube, or with the grid full, its bottom left corner.
+fn free_slot(tubes: &[Tube], width: f64, height: f64) -> Point {
+    let columns = ((width - CELL_SIZE as f64) / SLOT).floor().max(1.0) as usize;
+    let rows = ((height - CELL_SIZE as f64) / SLOT).floor().max(1.0) as usize;
+    let slot = |row: usize, column: usize| Point {
+        x: (column + 1) as f64 * SLOT,
+        y: (height - CELL_SIZE as f64 - row as f64 * SLOT).max(0.0),
+    };
+    (0..rows)
+        .flat_map(|row| (0..columns).map(move |column| (row, column)))
+        .map(|(row, column)| slot(row, column))
+        .find(|&p| {
+            tubes
                 .iter()
-                .enumerate()
-                .map(|(i, &f)| {
-                    let x = (i as f64 + 1.0) * CELL_SIZE as f64 * 2.5;
-                    let length = Tube::length(f);
-                    Tube::new(&audio, Point { x, y: 750.0 }, length)
-                })
-                .collect::<Vec<_>>(),
+                .all(|t| t.segment().distance_to(p) >= SLOT / 2.0)
+        })
+        .unwrap_or_else(|| slot(0, 0))
+}
+
+/// The number of frames lasting `seconds` in exported audio.
+fn frames(seconds: f64) -> usize {
+    (seconds.max(0.0) * EXPORT_SAMPLE_RATE).round() as usize
+}
+
+struct Audio<S = WebAudio> {
+    synth: S,
+    /// Every source started, paused and retuned while playing.
+    performance: Performance,
+}
+
+impl<S: Synth> Audio<S> {
+    fn new(synth: S) -> Self {
+        Audio {
+            synth,
+            performance: Performance::default(),
+        }
+    }
+
+    fn get_source(&mut self, frequency: f64) -> AudioSource {
+        let c = AS_COUNT.fetch_add(1, ::std::sync::atomic::Ordering::SeqCst);
+        self.synth.add_voice(c, frequency);
+        AudioSource {
+            c,
+            playing: false,
+            frequency: frequency,
+        }
+    }
+}
+
+use std::sync::atomic::AtomicUsize;
+static AS_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+struct AudioSource {
+    c: usize,
+    frequency: f64,
+    playing: bool,
+}
+
+impl AudioSource {
+    fn start<S: Synth>(&mut self, audio: &mut Audio<S>) {
+        self.strike(audio, 1.0);
+    }
+
+    /// Starts the source with its envelope peaking at `velocity`, from 0
+    /// to 1.
+    fn strike<S: Synth>(&mut self, audio: &mut Audio<S>, velocity: f64) {
+        if !self.playing {
+            self.playing = true;
+            audio.synth.start(self.c, velocity);
+            let now = audio.synth.current_time();
+            audio
+                .performance
+                .note_on(self.c, now, self.frequency, velocity);
+        }
+    }
+
+    /// Releases the source's voice, whether or not it is playing.
+    fn stop<S: Synth>(self, audio: &mut Audio<S>) {
+        audio.synth.stop(self.c);
+        if self.playing {
+            let now = audio.synth.current_time();
+            audio.performance.note_off(self.c, now);
+        }
+    }
+
+    fn set_frequency<S: Synth>(&mut self, audio: &mut Audio<S>, f: f64) {
+        self.frequency = f;
+        audio.synth.set_frequency(self.c, f);
+        if self.playing {
+            let now = audio.synth.current_time();
+            audio.performance.retune(self.c, now, f);
+        }
+    }
+
+    fn pause<S: Synth>(&mut self, audio: &mut Audio<S>) {
+        if self.playing {
+            self.playing = false;
+            audio.synth.pause(self.c);
+            let now = audio.synth.current_time();
+            audio.performance.note_off(self.c, now);
+        }
+    }
+}
+
+#[wasm_bindgen]
+impl Universe {
+    pub fn new(ac: AudioContext, ctx: CanvasRenderingContext2D) -> Self {
+        let mut audio = Audio::new(WebAudio::new(ac));
+        let medium = Medium::default();
+        let tuning = Tuning::default();
+        // The C major scale from C4 to C5.
+        let keys = [40, 42, 44, 45, 47, 49, 51, 52];
+
+        let tubes = keys
+            .iter()
+            .filter_map(|&key| tuning.frequency(key))
+            .enumerate()
+            .map(|(i, f)| {
+                let x = (i as f64 + 1.0) * CELL_SIZE as f64 * 2.5;
+                let speed = medium.speed_of_sound();
+                let length = Tube::length(f, DEFAULT_RADIUS, false, speed);
+                let base = Point { x, y: 750.0 };
+                let mut tube = Tube::new(&mut audio, base, Vector2::up(), length, speed);
+                tube.id = i as u32 + 1;
+                tube
+            })
+            .collect::<Vec<_>>();
+
+        Universe {
+            height: 60,
+            width: 60,
+            points: Vec::new(),
+            ctx,
+            active: None,
+            active_pt: None,
+            hover: None,
+            next_id: tubes.len() as u32,
+            tubes,
             audio: audio,
             stop_selected: true,
+            medium,
+            tuning,
+            history: History::default(),
+            drag: None,
+            sequence: Sequence::default(),
+            playback: None,
+            recorder: None,
+            sounding: Vec::new(),
+            controller: Controller::default(),
+            midi_notes: HashMap::new(),
+            bend: 0.0,
+            keymap: Keymap::default(),
+            performing: false,
+            analysing: false,
         }
     }
 
@@ -635,131 +974,1162 @@ impl Universe {
     }
 
     pub fn keypress(&mut self, key: &str, shift_key: bool) -> bool {
-        if let Some(idx) = self.tubes.iter().position(|t| t.selected) {
-            match key {
-                "d" => {
-                    let tube = self.tubes.remove(idx);
-                    tube.source.stop();
+        if self.performing {
+            if let Some(idx) = self.keyed_tube(key) {
+                self.sound(idx, 1.0);
+                return true;
+            }
+        }
+        let selected = self.selected();
+        if selected.is_empty() {
+            return false;
+        }
+        let edits = match key {
+            // Remove from the back so earlier indices stay valid.
+            "d" => selected
+                .iter()
+                .rev()
+                .map(|&index| Edit::Remove { index })
+                .collect(),
+            "o" => {
+                let open = !selected.iter().all(|&i| self.tubes[i].open);
+                selected
+                    .iter()
+                    .map(|&index| Edit::SetOpen { index, open })
+                    .collect()
+            }
+            "p" => {
+                let playing = !selected.iter().all(|&i| self.tubes[i].source.playing);
+                selected
+                    .iter()
+                    .map(|&index| Edit::SetPlaying { index, playing })
+                    .collect()
+            }
+            "]" | "[" => selected
+                .iter()
+                .map(|&index| Edit::SetMode {
+                    index,
+                    mode: self.tubes[index].step_mode(key == "]"),
+                })
+                .collect(),
+            "t" => selected
+                .iter()
+                .filter_map(|&index| {
+                    let nearest = self.tuning.nearest(self.tubes[index].source.frequency);
+                    self.tune_to_key(index, nearest.key)
+                })
+                .collect(),
+            // Several tubes move together by the step from the first one's
+            // nearest key, so detuned chords and other tunings keep their
+            // intervals.
+            "+" | "=" | "-" if selected.len() > 1 => {
+                let nearest = self
+                    .tuning
+                    .nearest(self.tubes[selected[0]].source.frequency);
+                let ratio = self
+                    .tuning
+                    .step(nearest.key, key != "-")
+                    .and_then(|target| {
+                        Some(self.tuning.frequency(target)? / self.tuning.frequency(nearest.key)?)
+                    });
+                let speed = self.medium.speed_of_sound();
+                selected
+                    .iter()
+                    .filter_map(|&index| {
+                        let tube = &self.tubes[index];
+                        let length = tube.tuned_length(tube.source.frequency * ratio?, speed);
+                        Some(Edit::SetLength { index, length })
+                    })
+                    .collect()
+            }
+            "+" | "=" | "-" => selected
+                .iter()
+                .filter_map(|&index| {
+                    let nearest = self.tuning.nearest(self.tubes[index].source.frequency);
+                    let target = self.tuning.step(nearest.key, key != "-")?;
+                    self.tune_to_key(index, target)
+                })
+                .collect(),
+            "ArrowUp" | "ArrowDown" => {
+                let step = if shift_key { 20.0 } else { 1.0 };
+                let step = if key == "ArrowUp" { step } else { -step };
+                selected
+                    .iter()
+                    .map(|&index| Edit::SetLength {
+                        index,
+                        length: (self.tubes[index].length + step).max(MIN_LENGTH),
+                    })
+                    .collect()
+            }
+            "ArrowRight" | "ArrowLeft" => {
+                let l = self.tubes.len();
+                let next = if key == "ArrowRight" {
+                    (selected[selected.len() - 1] + 1) % l
+                } else {
+                    (selected[0] + l - 1) % l
+                };
+                for idx in selected {
+                    self.select(idx, false);
+                }
+                self.select(next, true);
+                return true;
+            }
+            _ => {
+                return false;
+            }
+        };
+        self.perform_all(edits);
+        true
+    }
+
+    fn select(&mut self, idx: usize, v: bool) {
+        self.tubes[idx].set_selected(&mut self.audio, self.stop_selected, v);
+    }
+
+    /// Selects the clicked tube, or with `shift_key` adds it to or removes
+    /// it from the selection. Clicks elsewhere place the ends of a new tube.
+    pub fn clicked(&mut self, row: usize, col: usize, x: usize, y: usize, shift_key: bool) -> bool {
+        let clicked = Point {
+            x: x as f64,
+            y: y as f64,
+        };
+        if let Some(idx) = self.tubes.iter().position(|t| t.contains(clicked)) {
+            if shift_key {
+                let selected = self.tubes[idx].selected;
+                self.select(idx, !selected);
+            } else {
+                for i in self.selected() {
+                    self.select(i, false);
+                }
+                self.select(idx, true);
+            }
+            return true;
+        } else {
+            let selected = Cell::new(row, col);
+            if let Some(active) = self.active.take() {
+                let from = self.active_pt.take().unwrap();
+                self.hover = None;
+                if active != selected {
+                    let side = Segment::new(from, clicked);
+                    let index = self.tubes.len();
+                    let tube = TubeLayout::new(from, side.direction(), side.length());
+                    self.perform(Edit::Insert { index, tube });
+                }
+            } else {
+                self.active = Some(selected);
+                self.active_pt = Some(clicked);
+            }
+
+            return true;
+        }
+
+        false
+    }
+
+    /// Starts dragging the tube under the pointer: by its far end to resize
+    /// it, or by its body to move it. Pressing on empty space starts a box
+    /// selection, which adds to the current one with `shift_key`.
+    pub fn pointer_down(&mut self, x: f64, y: f64, shift_key: bool) -> bool {
+        let p = Point { x, y };
+        self.drag = if let Some(index) = self.tubes.iter().position(|t| t.grabs_end(p)) {
+            Some(Drag::Resize {
+                index,
+                origin: self.tubes[index].length,
+            })
+        } else if let Some(index) = self.tubes.iter().position(|t| t.contains(p)) {
+            let from = self.tubes[index].from;
+            Some(Drag::Move {
+                index,
+                grab: Vector2::from_segment(Segment::new(from, p)),
+                origin: from,
+            })
+        } else if self.active.is_none() {
+            Some(Drag::Select {
+                from: p,
+                to: p,
+                extend: shift_key,
+            })
+        } else {
+            None
+        };
+        self.drag.is_some()
+    }
+
+    /// Drags a tube, or updates the preview of the tube being created.
+    pub fn pointer_move(&mut self, x: f64, y: f64) -> bool {
+        let p = Point { x, y };
+        match self.drag {
+            Some(Drag::Move { index, grab, .. }) => {
+                self.tubes[index].from = p + grab;
+                true
+            }
+            Some(Drag::Resize { index, .. }) => {
+                let tube = &mut self.tubes[index];
+                let segment = tube.segment();
+                let axis = Vector2::from_segment(Segment::new(segment.to, segment.from));
+                let pointer = Vector2::from_segment(Segment::new(p, segment.from));
+                tube.length = (pointer.dot(axis) / axis.magnitude()).max(MIN_LENGTH);
+                tube.adjust_frequency(&mut self.audio, self.medium.speed_of_sound());
+                true
+            }
+            Some(Drag::Select { ref mut to, .. }) => {
+                *to = p;
+                true
+            }
+            None if self.active_pt.is_some() => {
+                self.hover = Some(p);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Finishes a drag, recording it for undo. Returns whether the drag
+    /// did anything, in which case the click that follows should be
+    /// ignored.
+    pub fn pointer_up(&mut self, x: f64, y: f64) -> bool {
+        self.pointer_move(x, y);
+        let inverse = match self.drag.take() {
+            Some(Drag::Select { from, to, extend }) => {
+                if from.distance_to(to) < DRAG_THRESHOLD {
+                    return false;
+                }
+                let boxed = |p: Point| between(p.x, from.x, to.x) && between(p.y, from.y, to.y);
+                for idx in 0..self.tubes.len() {
+                    let inside = boxed(self.tubes[idx].segment().midpoint());
+                    if inside || (!extend && self.tubes[idx].selected) {
+                        self.select(idx, inside || extend);
+                    }
+                }
+                return true;
+            }
+            Some(Drag::Move { index, origin, .. }) if self.tubes[index].from != origin => {
+                Edit::Move {
+                    index,
+                    from: origin,
+                }
+            }
+            Some(Drag::Resize { index, origin }) if self.tubes[index].length != origin => {
+                Edit::SetLength {
+                    index,
+                    length: origin,
+                }
+            }
+            _ => return false,
+        };
+        self.history.record(inverse);
+        true
+    }
+
+    pub fn draw_grid(&mut self) {
+        let mut ctx = &self.ctx;
+        self.draw_grid_on(&mut ctx);
+    }
+
+    pub fn draw_points(&mut self) {
+        {
+            let mut ctx = &self.ctx;
+            self.draw_points_on(&mut ctx);
+            if self.analysing {
+                let samples = self.audio.synth.waveform();
+                let sample_rate = self.audio.synth.ac.sample_rate();
+                Universe::draw_analysis_on(&mut ctx, &samples, sample_rate);
+            }
+        }
+        document
+            .body()
+            .query_selector("#rust-out")
+            .set_inner_html(&self.status());
+        let selected = self.selected();
+        if selected.len() > 1 {
+            let notes = selected
+                .iter()
+                .map(|&i| {
+                    let frequency = self.tubes[i].source.frequency;
+                    format!("{} ({:.2}Hz)", self.tuning.nearest(frequency), frequency)
+                })
+                .collect::<Vec<_>>();
+            document
+                .body()
+                .query_selector("#rust-out")
+                .set_inner_html(&format!(
+                    "{}<br><br>Selected {} tubes:<br>{}",
+                    self.status(),
+                    selected.len(),
+                    notes.join("<br>")
+                ));
+            return;
+        }
+        for tube in &self.tubes {
+            if tube.selected {
+                document
+                    .body()
+                    .query_selector("#rust-out")
+                    .set_inner_html(&format!(
+                        "{}<br><br>Selected {}tube:<br>Length: {:.3}m<br>Radius: {:.1}mm<br>\
+                         Harmonic: {}<br>Frequency: {:.2}<br>Note: {}",
+                        self.status(),
+                        if tube.open { "open " } else { "" },
+                        tube.length / PIXELS_PER_METER,
+                        tube.radius * 1000.0,
+                        tube.mode,
+                        tube.source.frequency,
+                        self.tuning.nearest(tube.source.frequency)
+                    ));
+            }
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        Layout {
+            version: layout::VERSION,
+            width: self.width,
+            height: self.height,
+            stop_selected: self.stop_selected,
+            sequence: self.sequence.clone(),
+            keymap: self.keymap.clone(),
+            medium: self.medium,
+            tuning: self.tuning.clone(),
+            tubes: self.tubes.iter().map(|t| t.to_layout()).collect(),
+        }
+        .to_json()
+    }
+
+    /// Replaces the current instrument with one saved by `to_json`.
+    pub fn load_json(&mut self, json: &str) -> bool {
+        let layout = match Layout::from_json(json) {
+            Ok(layout) => layout,
+            Err(e) => {
+                log(&format!("failed to load layout: {}", e));
+                return false;
+            }
+        };
+        self.stop_sequence();
+        self.recorder = None;
+        for tube in self.tubes.drain(..) {
+            tube.source.stop(&mut self.audio);
+        }
+        self.width = layout.width;
+        self.height = layout.height;
+        self.stop_selected = layout.stop_selected;
+        self.medium = layout.medium;
+        self.tuning = layout.tuning;
+        self.active = None;
+        self.active_pt = None;
+        self.hover = None;
+        self.history.clear();
+        self.drag = None;
+        self.sequence = layout.sequence;
+        self.keymap = layout.keymap;
+        self.next_id = layout.tubes.iter().map(|t| t.id).max().unwrap_or(0);
+        for tube in &layout.tubes {
+            let mut tube = Tube::from_layout(&mut self.audio, tube, self.medium.speed_of_sound());
+            if tube.id == 0 || self.tubes.iter().any(|t| t.id == tube.id) {
+                tube.id = self.fresh_id();
+            }
+            self.tubes.push(tube);
+        }
+        true
+    }
+
+    /// Fills the tubes with air at `temperature` °C and `humidity` percent
+    /// relative humidity.
+    pub fn set_air(&mut self, temperature: f64, humidity: f64) -> bool {
+        self.set_medium(Medium {
+            gas: Gas::Air,
+            temperature,
+            humidity,
+        })
+    }
+
+    /// Fills the tubes with a named gas ("air", "helium", "co2") at
+    /// `temperature` °C.
+    pub fn set_gas(&mut self, name: &str, temperature: f64) -> bool {
+        match Gas::from_name(name) {
+            Some(gas) => self.set_medium(Medium {
+                gas,
+                temperature,
+                humidity: 0.0,
+            }),
+            None => {
+                log(&format!("unknown gas {:?}", name));
+                false
+            }
+        }
+    }
+
+    pub fn speed_of_sound(&self) -> f64 {
+        self.medium.speed_of_sound()
+    }
+
+    /// Resizes the selected tubes to sound a named note such as "A4" or
+    /// "C#5".
+    pub fn tune_selected(&mut self, note: &str) -> bool {
+        let key = match piano::parse_key(note) {
+            Some(key) => key,
+            None => {
+                log(&format!("unknown note {:?}", note));
+                return false;
+            }
+        };
+        let edits = self
+            .selected()
+            .into_iter()
+            .filter_map(|idx| self.tune_to_key(idx, key))
+            .collect();
+        self.perform_all(edits)
+    }
+
+    pub fn undo(&mut self) -> bool {
+        match self.history.pop_undo() {
+            Some(edit) => {
+                let inverse = self.apply(edit);
+                self.history.push_redo(inverse);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn redo(&mut self) -> bool {
+        match self.history.pop_redo() {
+            Some(edit) => {
+                let inverse = self.apply(edit);
+                self.history.push_undo(inverse);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Switches to a built-in tuning ("equal", "just", "pythagorean" or
+    /// "meantone"), keeping the current reference pitch.
+    pub fn set_tuning(&mut self, name: &str) -> bool {
+        match Tuning::preset(name, self.tuning.reference_frequency) {
+            Some(tuning) => {
+                self.tuning = tuning;
+                true
+            }
+            None => {
+                log(&format!("unknown tuning {:?}", name));
+                false
+            }
+        }
+    }
+
+    /// Switches to a tuning read from the text of a Scala `.scl` file and an
+    /// optional (possibly empty) `.kbm` keyboard mapping.
+    pub fn load_scala(&mut self, scl: &str, kbm: &str) -> bool {
+        let kbm = if kbm.trim().is_empty() {
+            None
+        } else {
+            Some(kbm)
+        };
+        match Tuning::scala(scl, kbm, self.tuning.reference_frequency) {
+            Ok(tuning) => {
+                self.tuning = tuning;
+                true
+            }
+            Err(e) => {
+                log(&format!("failed to load Scala tuning: {}", e));
+                false
+            }
+        }
+    }
+
+    /// Sets the frequency of the tuning's reference key (A4 unless a
+    /// keyboard mapping says otherwise).
+    pub fn set_reference_pitch(&mut self, frequency: f64) {
+        if frequency.is_finite() && frequency > 0.0 {
+            self.tuning.reference_frequency = frequency;
+        }
+    }
+
+    /// Sets how quickly the selected tubes' harmonics fade: harmonic n has
+    /// amplitude 1/n^`rolloff`.
+    pub fn set_rolloff(&mut self, rolloff: f64) -> bool {
+        let mut changed = false;
+        for tube in self.tubes.iter_mut().filter(|t| t.selected) {
+            tube.rolloff = rolloff.max(0.0);
+            tube.adjust_timbre(&mut self.audio);
+            changed = true;
+        }
+        changed
+    }
+
+    /// Sets the attack/decay/sustain/release envelope of the selected tubes.
+    /// Times are in milliseconds and `sustain` is a fraction of the peak.
+    pub fn set_envelope(&mut self, attack: f64, decay: f64, sustain: f64, release: f64) -> bool {
+        let envelope = Envelope {
+            attack: attack / 1000.0,
+            decay: decay / 1000.0,
+            sustain,
+            release: release / 1000.0,
+        };
+        let mut changed = false;
+        for tube in self.tubes.iter_mut().filter(|t| t.selected) {
+            tube.set_envelope(&mut self.audio, envelope);
+            changed = true;
+        }
+        changed
+    }
+
+    /// Sets the inner radius of the selected tubes, in millimeters.
+    pub fn set_radius(&mut self, radius: f64) -> bool {
+        if !radius.is_finite() || radius <= 0.0 {
+            log(&format!("invalid radius: {}mm", radius));
+            return false;
+        }
+        let speed = self.medium.speed_of_sound();
+        let mut changed = false;
+        for tube in self.tubes.iter_mut().filter(|t| t.selected) {
+            tube.radius = radius / 1000.0;
+            tube.adjust_frequency(&mut self.audio, speed);
+            changed = true;
+        }
+        changed
+    }
+
+    /// Plays the sequence from its start.
+    pub fn play_sequence(&mut self) -> bool {
+        self.stop_sequence();
+        if self.sequence.events.is_empty() {
+            return false;
+        }
+        let origin = self.audio.synth.current_time() + SCHEDULE_AHEAD;
+        self.playback = Some(Playback::new(&self.sequence, origin));
+        self.tick();
+        true
+    }
+
+    /// Stops playing the sequence, releasing any notes still sounding.
+    pub fn stop_sequence(&mut self) {
+        self.instrument().stop_sequence();
+    }
+
+    /// Schedules the sequence notes due within the next `SCHEDULE_AHEAD`
+    /// seconds and frees the voices of finished ones. Call this every few
+    /// tens of milliseconds; returns whether the sequence is still playing.
+    pub fn tick(&mut self) -> bool {
+        let now = self.audio.synth.current_time();
+        let speed = self.medium.speed_of_sound();
+        if let Some(mut playback) = self.playback.take() {
+            schedule_notes(
+                &mut self.audio,
+                &self.tubes,
+                &self.sequence,
+                &mut playback,
+                now + SCHEDULE_AHEAD,
+                speed,
+                &mut self.sounding,
+            );
+            if !playback.is_finished(&self.sequence) {
+                self.playback = Some(playback);
+            }
+        }
+        release_finished(&mut self.audio, &mut self.sounding, now);
+        self.playback.is_some() || !self.sounding.is_empty()
+    }
+
+    /// Starts recording tubes played with "p" into the sequence.
+    pub fn record_sequence(&mut self) {
+        let now = self.audio.synth.current_time();
+        self.recorder = Some(Recorder::new(&self.sequence, now));
+        for idx in 0..self.tubes.len() {
+            if self.tubes[idx].source.playing {
+                self.record(idx, true);
+            }
+        }
+    }
+
+    /// Stops recording, adding the notes played to the sequence.
+    pub fn stop_recording(&mut self) -> bool {
+        let now = self.audio.synth.current_time();
+        match self.recorder.take() {
+            Some(recorder) => {
+                for event in recorder.finish(now) {
+                    if event.duration > 0.0 {
+                        self.sequence.add(event);
+                    }
                 }
-                "o" => {
-                    self.tubes[idx].open ^= true;
-                    self.tubes[idx].adjust_frequency();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Adds a note for each selected tube, starting at beat `start` and
+    /// lasting `duration` beats.
+    pub fn add_sequence_note(&mut self, start: f64, duration: f64, velocity: f64) -> bool {
+        let selected = self.selected();
+        for &idx in &selected {
+            let event = NoteEvent {
+                tube: self.tubes[idx].id,
+                start,
+                duration,
+                velocity: velocity.max(0.0).min(1.0),
+            };
+            if !event.is_playable() {
+                log(&format!(
+                    "cannot add a note at beat {} lasting {}",
+                    start, duration
+                ));
+                return false;
+            }
+            self.sequence.add(event);
+        }
+        !selected.is_empty()
+    }
+
+    pub fn clear_sequence(&mut self) {
+        self.stop_sequence();
+        self.sequence.events.clear();
+    }
+
+    pub fn tempo(&self) -> f64 {
+        self.sequence.tempo
+    }
+
+    /// Sets the sequence tempo in beats per minute. A playing sequence
+    /// carries on from the beat it has reached.
+    pub fn set_tempo(&mut self, tempo: f64) {
+        if tempo > 0.0 && tempo.is_finite() {
+            self.sequence.tempo = tempo;
+            let now = self.audio.synth.current_time();
+            if let Some(ref mut playback) = self.playback {
+                playback.set_tempo(&self.sequence, now);
+            }
+        }
+    }
+
+    /// Replaces the sequence with the notes of a Standard MIDI File. Each
+    /// pitch plays on a tube already tuned to it, or on a new tube stood in a
+    /// free spot on the grid. Loading can be undone.
+    pub fn load_midi(&mut self, data: &[u8]) -> bool {
+        let file = match midi::parse(data) {
+            Ok(file) => file,
+            Err(e) => {
+                log(&format!("failed to load MIDI file: {}", e));
+                return false;
+            }
+        };
+        self.stop_sequence();
+        let mut sequence = Sequence {
+            tempo: file.tempo,
+            events: Vec::new(),
+        };
+        let mut tubes = HashMap::new();
+        let mut added = Vec::new();
+        let mut off_keyboard = 0;
+        let mut silent = 0;
+        for note in &file.notes {
+            let key = match midi::to_key(note.number) {
+                Some(_) if note.duration <= 0.0 => {
+                    silent += 1;
+                    continue;
+                }
+                Some(key) => key,
+                None => {
+                    off_keyboard += 1;
+                    continue;
+                }
+            };
+            let tube = match tubes.get(&key).cloned() {
+                Some(id) => id,
+                None => {
+                    let id = self.tube_for_key(key, &mut added);
+                    tubes.insert(key, id);
+                    id
                 }
-                "p" => {
-                    let tube = &mut self.tubes[idx];
-                    if tube.source.playing {
-                        tube.source.pause(&self.audio);
-                    } else {
-                        tube.source.start();
+            };
+            // The file's notes are already in order of their start.
+            sequence.events.push(NoteEvent {
+                tube,
+                start: note.start,
+                duration: note.duration,
+                velocity: note.velocity as f64 / 127.0,
+            });
+        }
+        if off_keyboard > 0 {
+            log(&format!("skipped {} notes off the keyboard", off_keyboard));
+        }
+        if silent > 0 {
+            log(&format!("skipped {} notes with no duration", silent));
+        }
+        // Undoing the load brings back the old sequence as well as removing
+        // the tubes added for it.
+        added.push(self.apply(Edit::SetSequence(sequence)));
+        added.reverse();
+        self.history.record(Edit::Group(added));
+        true
+    }
+
+    /// Plays a message from a MIDI controller: note on and off, the sustain
+    /// pedal or pitch bend. Each note plays the tube nearest to it in pitch,
+    /// or a new tube stood in a free spot on the grid. Returns whether the
+    /// message did anything.
+    pub fn midi_message(&mut self, data: &[u8]) -> bool {
+        let actions = match self.controller.message(data) {
+            Ok(actions) => actions,
+            Err(e) => {
+                log(&format!("ignoring MIDI message: {}", e));
+                return false;
+            }
+        };
+        let changed = !actions.is_empty();
+        let speed = self.medium.speed_of_sound();
+        let mut added = Vec::new();
+        for action in actions {
+            match action {
+                Action::NoteOn { number, velocity } => {
+                    let key = match midi::to_key(number) {
+                        Some(key) => key,
+                        None => continue,
+                    };
+                    let id = self.tube_for_key(key, &mut added);
+                    self.midi_notes.insert(number, id);
+                    if let Some(idx) = self.tubes.iter().position(|t| t.id == id) {
+                        self.bend_tube(idx, speed);
+                        self.sound(idx, velocity as f64 / 127.0);
+                    }
+                }
+                Action::NoteOff { number } => {
+                    let id = match self.midi_notes.remove(&number) {
+                        Some(id) => id,
+                        None => continue,
+                    };
+                    // Another note may still be playing the same tube.
+                    if self.midi_notes.values().any(|&other| other == id) {
+                        continue;
+                    }
+                    if let Some(idx) = self.tubes.iter().position(|t| t.id == id) {
+                        self.silence(idx);
+                        self.tubes[idx].adjust_frequency(&mut self.audio, speed);
                     }
                 }
-                "ArrowUp" => {
-                    self.tubes[idx].length += if shift_key { 20.0 } else { 1.0 };
-                    self.tubes[idx].adjust_frequency();
+                Action::Bend(cents) => {
+                    self.bend = cents;
+                    let ids = self.midi_notes.values().cloned().collect::<Vec<_>>();
+                    for id in ids {
+                        if let Some(idx) = self.tubes.iter().position(|t| t.id == id) {
+                            self.bend_tube(idx, speed);
+                        }
+                    }
+                }
+            }
+        }
+        if !added.is_empty() {
+            added.reverse();
+            self.history.record(Edit::Group(added));
+        }
+        changed
+    }
+
+    /// Shows or hides the waveform and spectrum of the output. They change
+    /// as it plays, so draw every frame while they are shown.
+    pub fn set_analysis(&mut self, analysing: bool) {
+        self.analysing = analysing;
+    }
+
+    /// In performance mode, keys bound with `bind_key` play their tube from
+    /// `keypress` until `keyup`; other keys still edit the selected tubes.
+    pub fn set_performance_mode(&mut self, performing: bool) {
+        self.performing = performing;
+    }
+
+    /// Releases the tube played by `key` in performance mode.
+    pub fn keyup(&mut self, key: &str) -> bool {
+        if !self.performing {
+            return false;
+        }
+        match self.keyed_tube(key) {
+            Some(idx) => {
+                self.silence(idx);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Makes `key` play the selected tube in performance mode.
+    pub fn bind_key(&mut self, key: &str) -> bool {
+        let selected = self.selected();
+        if key.is_empty() || selected.len() != 1 {
+            log("select a single tube to bind a key to");
+            return false;
+        }
+        self.keymap.bind(key, self.tubes[selected[0]].id);
+        true
+    }
+
+    pub fn unbind_key(&mut self, key: &str) -> bool {
+        self.keymap.unbind(key)
+    }
+
+    /// The tubes playing now as a WAV file: `seconds`, up to a minute, of
+    /// them sounding and then their release. `bits` is 16 or 24 for integer
+    /// samples, or 32 for floating point.
+    pub fn export_wav(&self, seconds: f64, bits: u32) -> Vec<u8> {
+        let format = match wav::Format::from_bits(bits) {
+            Some(format) => format,
+            None => {
+                log(&format!("unsupported WAV sample size: {} bits", bits));
+                return Vec::new();
+            }
+        };
+        if !seconds.is_finite() || seconds < 0.0 {
+            log(&format!("cannot export {} seconds of audio", seconds));
+            return Vec::new();
+        }
+        let seconds = seconds.min(MAX_EXPORT_SECONDS);
+        let mut samples = render_tubes(&self.tubes, self.medium.speed_of_sound(), seconds);
+        wav::limit(&mut samples, EXPORT_PEAK);
+        wav::encode(&samples, EXPORT_SAMPLE_RATE as u32, format)
+    }
+
+    /// The sequence, up to ten minutes of it, as a WAV file, with `bits` as
+    /// for `export_wav`.
+    pub fn export_sequence_wav(&self, bits: u32) -> Vec<u8> {
+        let format = match wav::Format::from_bits(bits) {
+            Some(format) => format,
+            None => {
+                log(&format!("unsupported WAV sample size: {} bits", bits));
+                return Vec::new();
+            }
+        };
+        let speed = self.medium.speed_of_sound();
+        let mut samples = render_sequence(&self.tubes, &self.sequence, speed, MAX_SEQUENCE_SECONDS);
+        if samples.len() >= frames(MAX_SEQUENCE_SECONDS) {
+            log(&format!(
+                "the sequence is cut off after {} seconds",
+                MAX_SEQUENCE_SECONDS
+            ));
+        }
+        wav::limit(&mut samples, EXPORT_PEAK);
+        wav::encode(&samples, EXPORT_SAMPLE_RATE as u32, format)
+    }
+
+    /// Everything played on the tubes by hand since the page loaded, or the
+    /// performance was last cleared, as a Standard MIDI File.
+    pub fn export_midi(&self) -> Vec<u8> {
+        if self.audio.performance.is_empty() {
+            log("nothing has been played to export");
+        }
+        self.audio
+            .performance
+            .to_midi(self.audio.synth.current_time())
+    }
+
+    pub fn clear_performance(&mut self) {
+        self.audio.performance.clear();
+    }
+
+    /// Renders the grid and tubes as a standalone SVG document.
+    pub fn to_svg(&self) -> String {
+        let mut svg = SvgSurface::new(
+            (CELL_SIZE * self.width) as f64,
+            (CELL_SIZE * self.height) as f64,
+        );
+        self.draw_grid_on(&mut svg);
+        self.draw_points_on(&mut svg);
+        svg.finish()
+    }
+}
+
+/// The parts of a `Universe` that edits change, borrowed apart from the
+/// canvas so that edits can be applied to any synth.
+struct Instrument<'a, S: Synth + 'a> {
+    tubes: &'a mut Vec<Tube>,
+    audio: &'a mut Audio<S>,
+    sequence: &'a mut Sequence,
+    playback: &'a mut Option<Playback>,
+    recorder: &'a mut Option<Recorder>,
+    sounding: &'a mut Vec<(AudioSource, f64)>,
+    next_id: &'a mut u32,
+    speed: f64,
+}
+
+impl<'a, S: Synth> Instrument<'a, S> {
+    fn fresh_id(&mut self) -> u32 {
+        *self.next_id += 1;
+        *self.next_id
+    }
+
+    /// Notes tube `idx` starting or stopping in the recording, if any.
+    fn record(&mut self, idx: usize, playing: bool) {
+        let now = self.audio.synth.current_time();
+        let id = self.tubes[idx].id;
+        if let Some(ref mut recorder) = *self.recorder {
+            if playing {
+                recorder.note_on(id, now);
+            } else {
+                recorder.note_off(id, now);
+            }
+        }
+    }
+
+    fn stop_sequence(&mut self) {
+        *self.playback = None;
+        for (source, _) in self.sounding.drain(..) {
+            source.stop(self.audio);
+        }
+    }
+
+    /// Applies `edit`, returning the edit that reverts it.
+    fn apply(&mut self, edit: Edit) -> Edit {
+        let speed = self.speed;
+        match edit {
+            Edit::Insert { index, tube } => {
+                let mut tube = Tube::from_layout(self.audio, &tube, speed);
+                if tube.id == 0 {
+                    tube.id = self.fresh_id();
                 }
-                "ArrowDown" => {
-                    self.tubes[idx].length -= if shift_key { 20.0 } else { 1.0 };
-                    if self.tubes[idx].length < 10.0 {
-                        self.tubes[idx].length = 10.0;
-                    }
-                    self.tubes[idx].adjust_frequency();
+                self.tubes.insert(index, tube);
+                if self.tubes[index].source.playing {
+                    self.record(index, true);
                 }
-                "ArrowRight" => {
-                    let l = self.tubes.len();
-                    self.select(idx, false);
-                    self.select((idx + 1) % l, true);
+                Edit::Remove { index }
+            }
+            Edit::Remove { index } => {
+                self.record(index, false);
+                let tube = self.tubes.remove(index);
+                let layout = tube.to_layout();
+                tube.source.stop(self.audio);
+                Edit::Insert {
+                    index,
+                    tube: layout,
                 }
-                "ArrowLeft" => {
-                    self.select(idx, false);
-                    if idx == 0 {
-                        let l = self.tubes.len();
-                        self.select(l - 1, true);
-                    } else {
-                        self.select(idx - 1, true);
-                    }
+            }
+            Edit::SetLength { index, length } => {
+                let tube = &mut self.tubes[index];
+                let old = mem::replace(&mut tube.length, length);
+                tube.adjust_frequency(self.audio, speed);
+                Edit::SetLength { index, length: old }
+            }
+            Edit::Move { index, from } => {
+                let old = mem::replace(&mut self.tubes[index].from, from);
+                Edit::Move { index, from: old }
+            }
+            Edit::SetOpen { index, open } => {
+                let old = self.tubes[index].open;
+                self.tubes[index].set_open(self.audio, open, speed);
+                Edit::SetOpen { index, open: old }
+            }
+            Edit::SetMode { index, mode } => {
+                let old = self.tubes[index].mode;
+                self.tubes[index].set_mode(self.audio, mode, speed);
+                Edit::SetMode { index, mode: old }
+            }
+            Edit::SetPlaying { index, playing } => {
+                let old = self.tubes[index].source.playing;
+                if playing {
+                    self.tubes[index].source.start(self.audio);
+                } else {
+                    self.tubes[index].source.pause(self.audio);
                 }
-                _ => {
-                    return false;
+                self.record(index, playing);
+                Edit::SetPlaying {
+                    index,
+                    playing: old,
                 }
             }
-            return true;
+            Edit::SetSequence(sequence) => {
+                self.stop_sequence();
+                Edit::SetSequence(mem::replace(self.sequence, sequence))
+            }
+            Edit::Group(edits) => {
+                let mut inverses = edits
+                    .into_iter()
+                    .map(|edit| self.apply(edit))
+                    .collect::<Vec<_>>();
+                inverses.reverse();
+                Edit::Group(inverses)
+            }
         }
-        false
     }
+}
 
-    fn select(&mut self, idx: usize, v: bool) {
-        self.tubes[idx].set_selected(&self.audio, self.stop_selected, v);
+impl Universe {
+    fn instrument<'a>(&'a mut self) -> Instrument<'a, WebAudio> {
+        Instrument {
+            tubes: &mut self.tubes,
+            audio: &mut self.audio,
+            sequence: &mut self.sequence,
+            playback: &mut self.playback,
+            recorder: &mut self.recorder,
+            sounding: &mut self.sounding,
+            next_id: &mut self.next_id,
+            speed: self.medium.speed_of_sound(),
+        }
     }
 
-    pub fn clicked(&mut self, row: usize, col: usize, x: usize, y: usize) -> bool {
-        let clicked = Point {
-            x: x as f64,
-            y: y as f64,
-        };
-        let selected = self.tubes.iter().find(|t| t.contains(clicked)).is_some();
-        if selected {
-            for tube in &mut self.tubes {
-                tube.set_selected(&self.audio, self.stop_selected, false);
-            }
-            let tube = self.tubes.iter_mut().find(|t| t.contains(clicked)).unwrap();
-            tube.set_selected(&self.audio, self.stop_selected, true);
-            return true;
-        } else {
-            let selected = Cell::new(row, col);
-            if let Some(active) = self.active.take() {
-                let from = self.active_pt.take().unwrap();
-                let to = clicked;
-                if active != selected {
-                    let mut side = Segment { from: from, to: to };
-                    let base = if from.y > to.y { from } else { to };
-                    self.tubes.push(Tube::new(&self.audio, base, side.length()));
-                }
-            } else {
-                self.active = Some(selected);
-                self.active_pt = Some(clicked);
-            }
+    fn fresh_id(&mut self) -> u32 {
+        self.instrument().fresh_id()
+    }
 
-            return true;
+    /// Notes tube `idx` starting or stopping in the recording, if any.
+    fn record(&mut self, idx: usize, playing: bool) {
+        self.instrument().record(idx, playing);
+    }
+
+    /// The id of the tube nearest in pitch to `key`, adding one if none is
+    /// within `MATCH_CENTS`. The edit that reverts adding it goes on `added`.
+    fn tube_for_key(&mut self, key: usize, added: &mut Vec<Edit>) -> u32 {
+        let frequency = self
+            .tuning
+            .frequency(key)
+            .unwrap_or_else(|| piano::frequency(key));
+        let speed = self.medium.speed_of_sound();
+        let nearest = self
+            .tubes
+            .iter()
+            .map(|t| (t.id, piano::cents(t.frequency(speed), frequency).abs()))
+            .filter(|&(_, cents)| cents <= MATCH_CENTS)
+            .fold(
+                None,
+                |nearest: Option<(u32, f64)>, (id, cents)| match nearest {
+                    Some((_, best)) if best <= cents => nearest,
+                    _ => Some((id, cents)),
+                },
+            );
+        if let Some((id, _)) = nearest {
+            return id;
         }
+        let base = free_slot(
+            &self.tubes,
+            (CELL_SIZE * self.width) as f64,
+            (CELL_SIZE * self.height) as f64,
+        );
+        let length = Tube::length(frequency, DEFAULT_RADIUS, false, speed);
+        let index = self.tubes.len();
+        let tube = TubeLayout::new(base, Vector2::up(), length);
+        added.push(self.apply(Edit::Insert { index, tube }));
+        self.tubes[index].id
+    }
 
-        false
+    /// The index of the tube `key` plays in performance mode.
+    fn keyed_tube(&self, key: &str) -> Option<usize> {
+        let id = self.keymap.tube(key)?;
+        self.tubes.iter().position(|t| t.id == id)
     }
 
-    fn draw_rect(&mut self, x1: usize, y1: usize, x2: usize, y2: usize) {
-        self.ctx.begin_path();
-        self.ctx.move_to(x1, y1);
-        self.ctx.line_to(x2, y1);
-        self.ctx.line_to(x2, y2);
-        self.ctx.line_to(x1, y2);
-        self.ctx.line_to(x1, y1);
-        self.ctx.stroke();
+    /// Starts tube `idx` as part of a performance rather than an edit.
+    fn sound(&mut self, idx: usize, velocity: f64) {
+        self.tubes[idx].source.strike(&mut self.audio, velocity);
+        self.record(idx, true);
     }
 
-    pub fn draw_grid(&mut self) {
-        self.ctx.begin_path();
-        self.ctx
-        // todo: 1.0 / window.devicePixelRatio
-            .set_line_width(0.5);
-        self.ctx.set_stroke_style("#CCCCCC");
+    fn silence(&mut self, idx: usize) {
+        self.tubes[idx].source.pause(&mut self.audio);
+        self.record(idx, false);
+    }
 
-        // vertical lines
-        for i in 0..=self.width {
-            self.ctx.move_to(i * CELL_SIZE, 0);
-            self.ctx.line_to(i * CELL_SIZE, CELL_SIZE * self.height);
+    /// Retunes tube `idx` to its own pitch bent by the MIDI controller.
+    fn bend_tube(&mut self, idx: usize, speed: f64) {
+        let tube = &mut self.tubes[idx];
+        let frequency = tube.frequency(speed) * 2f64.powf(self.bend / 1200.0);
+        tube.source.set_frequency(&mut self.audio, frequency);
+    }
+
+    /// Indices of the selected tubes, in order.
+    fn selected(&self) -> Vec<usize> {
+        (0..self.tubes.len())
+            .filter(|&i| self.tubes[i].selected)
+            .collect()
+    }
+
+    /// The edit that resizes tube `idx` to sound `key`, if the tuning maps
+    /// that key.
+    fn tune_to_key(&self, idx: usize, key: usize) -> Option<Edit> {
+        let frequency = self.tuning.frequency(key)?;
+        let length = self.tubes[idx].tuned_length(frequency, self.medium.speed_of_sound());
+        Some(Edit::SetLength { index: idx, length })
+    }
+
+    /// Applies a user edit and records how to revert it.
+    fn perform(&mut self, edit: Edit) {
+        let inverse = self.apply(edit);
+        self.history.record(inverse);
+    }
+
+    /// Performs `edits` as one undoable step. Returns false if there were
+    /// none.
+    fn perform_all(&mut self, mut edits: Vec<Edit>) -> bool {
+        match edits.len() {
+            0 => return false,
+            1 => self.perform(edits.remove(0)),
+            _ => self.perform(Edit::Group(edits)),
         }
+        true
+    }
 
-        // horizontal lines
-        for i in 0..=self.height {
-            self.ctx.move_to(0, i * CELL_SIZE);
-            self.ctx.line_to(CELL_SIZE * self.width, i * CELL_SIZE);
+    /// Applies `edit`, returning the edit that reverts it.
+    fn apply(&mut self, edit: Edit) -> Edit {
+        // Edits may shift tube indices under an ongoing drag.
+        self.drag = None;
+        self.instrument().apply(edit)
+    }
+
+    fn set_medium(&mut self, medium: Medium) -> bool {
+        if let Err(e) = medium.validate() {
+            log(&format!("invalid medium: {}", e));
+            return false;
+        }
+        self.medium = medium;
+        let speed = medium.speed_of_sound();
+        for tube in &mut self.tubes {
+            tube.adjust_frequency(&mut self.audio, speed);
+        }
+        true
+    }
+
+    fn status(&self) -> String {
+        let mut info = format!(
+            "Tuning: {}, reference {:.1}Hz<br>Medium: {} at {:.1}°C",
+            self.tuning.name,
+            self.tuning.reference_frequency,
+            self.medium.gas.name(),
+            self.medium.temperature
+        );
+        if self.medium.gas == Gas::Air {
+            info += &format!(", {:.0}% humidity", self.medium.humidity);
+        }
+        info += &format!("<br>Speed of sound: {:.1}m/s", self.medium.speed_of_sound());
+        info += &format!(
+            "<br>Sequence: {} notes at {} bpm",
+            self.sequence.events.len(),
+            self.sequence.tempo
+        );
+        if self.recorder.is_some() {
+            info += ", recording";
+        } else if self.playback.is_some() {
+            info += ", playing";
         }
+        if self.performing {
+            info += "<br>Performance mode: bound keys play tubes";
+        }
+        info
+    }
+
+    fn draw_rect<S: Surface>(ctx: &mut S, x1: usize, y1: usize, x2: usize, y2: usize) {
+        let (x1, y1, x2, y2) = (x1 as f64, y1 as f64, x2 as f64, y2 as f64);
+        ctx.begin_path();
+        ctx.move_to(x1, y1);
+        ctx.line_to(x2, y1);
+        ctx.line_to(x2, y2);
+        ctx.line_to(x1, y2);
+        ctx.line_to(x1, y1);
+        ctx.stroke();
+    }
 
-        self.ctx.stroke();
+    fn draw_grid_on<S: Surface>(&self, ctx: &mut S) {
+        Universe::draw_lines_on(ctx, self.width, self.height);
 
         if let Some(Cell { row: y, col: x }) = self.active {
-            self.ctx.set_stroke_style("#f00");
-            self.ctx.set_line_width(3.0);
-            self.draw_rect(
+            ctx.set_stroke_style("#f00");
+            ctx.set_line_width(3.0);
+            Universe::draw_rect(
+                ctx,
                 x * CELL_SIZE,
                 y * CELL_SIZE,
                 (x + 1) * CELL_SIZE,
@@ -768,38 +2138,204 @@ impl Universe {
         }
     }
 
-    pub fn draw_points(&mut self) {
-        self.ctx.set_stroke_style("#00000");
+    /// The lines of a grid `width` by `height` cells.
+    fn draw_lines_on<S: Surface>(ctx: &mut S, width: usize, height: usize) {
+        ctx.begin_path();
+        // todo: 1.0 / window.devicePixelRatio
+        ctx.set_line_width(0.5);
+        ctx.set_stroke_style("#CCCCCC");
+
+        // vertical lines
+        for i in 0..=width {
+            ctx.move_to((i * CELL_SIZE) as f64, 0.0);
+            ctx.line_to((i * CELL_SIZE) as f64, (CELL_SIZE * height) as f64);
+        }
+
+        // horizontal lines
+        for i in 0..=height {
+            ctx.move_to(0.0, (i * CELL_SIZE) as f64);
+            ctx.line_to((CELL_SIZE * width) as f64, (i * CELL_SIZE) as f64);
+        }
+
+        ctx.stroke();
+    }
+
+    fn draw_points_on<S: Surface>(&self, ctx: &mut S) {
+        ctx.set_stroke_style("#00000");
         for &Cell { col: x, row: y } in &self.points {
-            self.ctx
-                .fill_rect(x * CELL_SIZE, y * CELL_SIZE, CELL_SIZE, CELL_SIZE);
+            ctx.fill_rect(
+                (x * CELL_SIZE) as f64,
+                (y * CELL_SIZE) as f64,
+                CELL_SIZE as f64,
+                CELL_SIZE as f64,
+            );
         }
-        document
-            .body()
-            .query_selector("#rust-out")
-            .set_inner_html("");
         for tube in &self.tubes {
-            tube.draw(&mut self.ctx);
-            if tube.selected {
-                document
-                    .body()
-                    .query_selector("#rust-out")
-                    .set_inner_html(&format!(
-                        "Selected {}tube:<br>Length: {:.3}m<br>Frequency: {:.2}{}",
-                        if tube.open { "open " } else { "" },
-                        tube.length / PIXELS_PER_METER,
-                        tube.source.frequency,
-                        if let Some((_, s)) = piano::NOTES
-                            .iter()
-                            .find(|(f, _)| *f == tube.source.frequency as usize)
-                        {
-                            format!("<br>Note: {}", s)
-                        } else {
-                            "".to_string()
-                        }
-                    ));
+            tube.draw(ctx);
+        }
+        if self.performing {
+            ctx.set_fill_style("#000");
+            for tube in &self.tubes {
+                let keys = self.keymap.keys_for(tube.id);
+                if !keys.is_empty() {
+                    // Just beyond the closed end.
+                    let at = tube.from + tube.direction * -(tube.width() / 2.0 + 12.0);
+                    ctx.fill_text(&keys.join(" "), at.x - 4.0, at.y + 4.0);
+                }
+            }
+        }
+        if let (Some(from), Some(to)) = (self.active_pt, self.hover) {
+            self.draw_pending_on(ctx, from, to);
+        }
+        if let Some(Drag::Select { from, to, .. }) = self.drag {
+            ctx.set_stroke_style("#f0f");
+            ctx.set_line_width(1.0);
+            ctx.begin_path();
+            ctx.move_to(from.x, from.y);
+            ctx.line_to(to.x, from.y);
+            ctx.line_to(to.x, to.y);
+            ctx.line_to(from.x, to.y);
+            ctx.line_to(from.x, from.y);
+            ctx.stroke();
+        }
+    }
+
+    /// Outlines the tube a click at `to` would create, labelled with what
+    /// it would sound like.
+    fn draw_pending_on<S: Surface>(&self, ctx: &mut S, from: Point, to: Point) {
+        let side = Segment::new(from, to);
+        let length = side.length();
+        side.draw(ctx, "#bbb");
+        if length > 0.0 {
+            side.to_rectangle(2.0 * DEFAULT_RADIUS * PIXELS_PER_METER)
+                .draw(ctx, "#f80");
+        }
+
+        let frequency =
+            Tube::frequency_for(length, DEFAULT_RADIUS, false, self.medium.speed_of_sound());
+        ctx.set_fill_style("#000");
+        ctx.fill_text(
+            &format!(
+                "{:.3}m {:.2}Hz {}",
+                length / PIXELS_PER_METER,
+                frequency,
+                self.tuning.nearest(frequency)
+            ),
+            to.x + 12.0,
+            to.y - 12.0,
+        );
+    }
+
+    /// Draws the waveform of `samples` above their spectrum, with the
+    /// strongest partials labelled by note, in the top left corner.
+    fn draw_analysis_on<S: Surface>(ctx: &mut S, samples: &[f32], sample_rate: f64) {
+        let (left, top) = (10.0, 10.0);
+        ctx.set_fill_style("rgba(255, 255, 255, 0.9)");
+        ctx.fill_rect(left, top, ANALYSIS_WIDTH, WAVEFORM_HEIGHT + SPECTRUM_HEIGHT);
+        ctx.set_line_width(1.0);
+
+        ctx.set_stroke_style("#08f");
+        ctx.begin_path();
+        for (i, &sample) in samples.iter().enumerate() {
+            let x = left + ANALYSIS_WIDTH * i as f64 / samples.len() as f64;
+            let y = top + WAVEFORM_HEIGHT / 2.0 * (1.0 - (sample as f64).max(-1.0).min(1.0));
+            if i == 0 {
+                ctx.move_to(x, y);
+            } else {
+                ctx.line_to(x, y);
+            }
+        }
+        ctx.stroke();
+
+        // Frequency runs on a log scale up to Nyquist, and level in
+        // decibels down to the floor.
+        let spectrum = spectrum::spectrum(samples);
+        let nyquist = sample_rate / 2.0;
+        let to_x = |frequency: f64| {
+            left + ANALYSIS_WIDTH * (frequency / SPECTRUM_LOW).ln() / (nyquist / SPECTRUM_LOW).ln()
+        };
+        let to_y = |magnitude: f64| {
+            let decibels = 20.0 * magnitude.max(1e-9).log10();
+            top + WAVEFORM_HEIGHT + SPECTRUM_HEIGHT * (decibels / SPECTRUM_FLOOR).max(0.0).min(1.0)
+        };
+        ctx.set_stroke_style("#f80");
+        ctx.begin_path();
+        let bins = spectrum
+            .iter()
+            .enumerate()
+            .map(|(k, &magnitude)| (k as f64 * sample_rate / samples.len() as f64, magnitude))
+            .filter(|&(frequency, _)| frequency >= SPECTRUM_LOW);
+        for (i, (frequency, magnitude)) in bins.enumerate() {
+            if i == 0 {
+                ctx.move_to(to_x(frequency), to_y(magnitude));
+            } else {
+                ctx.line_to(to_x(frequency), to_y(magnitude));
             }
         }
+        ctx.stroke();
+
+        ctx.set_fill_style("#000");
+        for peak in spectrum::peaks(&spectrum, sample_rate, LABELLED_PEAKS, PEAK_FLOOR) {
+            ctx.fill_text(
+                &format!("{} ({:.0}Hz)", peak.note(), peak.frequency),
+                to_x(peak.frequency) + 2.0,
+                to_y(peak.magnitude) - 2.0,
+            );
+        }
+    }
+}
+
+impl<'a> Surface for &'a CanvasRenderingContext2D {
+    fn begin_path(&mut self) {
+        CanvasRenderingContext2D::begin_path(self);
+    }
+
+    fn move_to(&mut self, x: f64, y: f64) {
+        CanvasRenderingContext2D::move_to(self, x, y);
+    }
+
+    fn line_to(&mut self, x: f64, y: f64) {
+        CanvasRenderingContext2D::line_to(self, x, y);
+    }
+
+    fn arc(
+        &mut self,
+        x: f64,
+        y: f64,
+        radius: f64,
+        start_angle: f64,
+        end_angle: f64,
+        anticlockwise: bool,
+    ) {
+        CanvasRenderingContext2D::arc(self, x, y, radius, start_angle, end_angle, anticlockwise);
+    }
+
+    fn set_line_width(&mut self, width: f64) {
+        CanvasRenderingContext2D::set_line_width(self, width);
+    }
+
+    fn set_stroke_style(&mut self, style: &str) {
+        CanvasRenderingContext2D::set_stroke_style(self, style);
+    }
+
+    fn set_fill_style(&mut self, style: &str) {
+        CanvasRenderingContext2D::set_fill_style(self, style);
+    }
+
+    fn stroke(&mut self) {
+        CanvasRenderingContext2D::stroke(self);
+    }
+
+    fn fill(&mut self) {
+        CanvasRenderingContext2D::fill(self);
+    }
+
+    fn fill_rect(&mut self, x: f64, y: f64, width: f64, height: f64) {
+        CanvasRenderingContext2D::fill_rect(self, x, y, width, height);
+    }
+
+    fn fill_text(&mut self, text: &str, x: f64, y: f64) {
+        CanvasRenderingContext2D::fill_text(self, text, x, y);
     }
 }
 
@@ -818,23 +2354,21 @@ extern "C" {
     #[wasm_bindgen(method, setter = fillStyle)]
     fn set_fill_style(this: &CanvasRenderingContext2D, style: &str);
     #[wasm_bindgen(method, js_name = moveTo)]
-    fn move_to(this: &CanvasRenderingContext2D, x: usize, y: usize);
-    #[wasm_bindgen(method, js_name = lineTo)]
-    fn line_to(this: &CanvasRenderingContext2D, x: usize, y: usize);
+    fn move_to(this: &CanvasRenderingContext2D, x: f64, y: f64);
     #[wasm_bindgen(method, js_name = lineTo)]
-    fn line_to_float(this: &CanvasRenderingContext2D, x: f64, y: f64);
+    fn line_to(this: &CanvasRenderingContext2D, x: f64, y: f64);
     #[wasm_bindgen(method)]
     fn arc(
         this: &CanvasRenderingContext2D,
-        x: usize,
-        y: usize,
+        x: f64,
+        y: f64,
         radius: f64,
         start_angle: f64,
         end_angle: f64,
         anticlockwise: bool,
     );
     #[wasm_bindgen(method, js_name = fillRect)]
-    fn fill_rect(this: &CanvasRenderingContext2D, x: usize, y: usize, width: usize, height: usize);
+    fn fill_rect(this: &CanvasRenderingContext2D, x: f64, y: f64, width: f64, height: f64);
     #[wasm_bindgen(method)]
     fn stroke(this: &CanvasRenderingContext2D);
     #[wasm_bindgen(method)]
@@ -864,6 +2398,21 @@ extern "C" {
     fn create_oscillator(this: &AudioContext) -> OscillatorNode;
     #[wasm_bindgen(method, getter)]
     fn destination(this: &AudioContext) -> AudioNode;
+    #[wasm_bindgen(method, getter = currentTime)]
+    fn current_time(this: &AudioContext) -> f64;
+    #[wasm_bindgen(method, getter = sampleRate)]
+    fn sample_rate(this: &AudioContext) -> f64;
+    #[wasm_bindgen(method, js_name = createAnalyser)]
+    fn create_analyser(this: &AudioContext) -> AnalyserNode;
+    #[wasm_bindgen(method, js_name = createPeriodicWave)]
+    fn create_periodic_wave(
+        this: &AudioContext,
+        real: &[f32],
+        imag: &[f32],
+        constraints: &JsValue,
+    ) -> PeriodicWave;
+
+    type PeriodicWave;
 
     type OscillatorNode;
     #[wasm_bindgen(method)]
@@ -876,12 +2425,20 @@ extern "C" {
     fn disconnect(this: &OscillatorNode);
     #[wasm_bindgen(method, getter)]
     fn frequency(this: &OscillatorNode) -> AudioParam;
+    #[wasm_bindgen(method, js_name = setPeriodicWave)]
+    fn set_periodic_wave(this: &OscillatorNode, wave: &PeriodicWave);
 
     type AudioParam;
+    #[wasm_bindgen(method, getter)]
+    fn value(this: &AudioParam) -> f64;
     #[wasm_bindgen(method, setter)]
     fn set_value(this: &AudioParam, v: f64);
+    #[wasm_bindgen(method, js_name = setValueAtTime)]
+    fn set_value_at_time(this: &AudioParam, v: f64, t: f64);
     #[wasm_bindgen(method, js_name = linearRampToValueAtTime)]
     fn linear_ramp_to(this: &AudioParam, v: f64, t: f64);
+    #[wasm_bindgen(method, js_name = cancelScheduledValues)]
+    fn cancel_scheduled_values(this: &AudioParam, t: f64);
 
     type GainNode;
     #[wasm_bindgen(method, getter)]
@@ -891,7 +2448,473 @@ extern "C" {
     fn connect(this: &GainNode, node: &AudioNode);
     #[wasm_bindgen(method, js_name = connect)]
     fn connect_to_gain(this: &GainNode, node: &GainNode);
+    #[wasm_bindgen(method)]
+    fn disconnect(this: &GainNode);
+    #[wasm_bindgen(method, js_name = connect)]
+    fn connect_to_analyser(this: &GainNode, node: &AnalyserNode);
+
+    type AnalyserNode;
+    #[wasm_bindgen(method, setter = fftSize)]
+    fn set_fft_size(this: &AnalyserNode, size: u32);
+    #[wasm_bindgen(method, js_name = getFloatTimeDomainData)]
+    fn get_float_time_domain_data(this: &AnalyserNode, data: &mut [f32]);
 
     #[wasm_bindgen(js_name = setTimeout)]
     fn set_timeout(cb: &Closure<FnMut()>, delay: u32) -> f64;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use synth::OfflineSynth;
+
+    const SAMPLE_RATE: f64 = 44100.0;
+    const SPEED: f64 = 343.0;
+
+    fn offline() -> Audio<OfflineSynth> {
+        Audio::new(OfflineSynth::new(SAMPLE_RATE))
+    }
+
+    /// A closed tube at the origin sounding `frequency`.
+    fn tube(audio: &mut Audio<OfflineSynth>, frequency: f64) -> Tube {
+        let length = Tube::length(frequency, DEFAULT_RADIUS, false, SPEED);
+        Tube::new(audio, Point::origin(), Vector2::up(), length, SPEED)
+    }
+
+    fn peak(samples: &[f32]) -> f32 {
+        samples.iter().fold(0.0, |peak, s| peak.max(s.abs()))
+    }
+
+    fn svg_of(tube: &Tube) -> String {
+        let mut svg = SvgSurface::new(40.0, 40.0);
+        Universe::draw_lines_on(&mut svg, 2, 2);
+        tube.draw(&mut svg);
+        svg.finish()
+    }
+
+    /// A 2 by 2 grid with a short closed tube outlined in `stroke`.
+    fn golden_svg(stroke: &str) -> String {
+        format!(
+            concat!(
+                "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"40\" height=\"40\" viewBox=\"0 0 40 40\">\n",
+                "<path d=\"M0 0 L0 40 M20 0 L20 40 M40 0 L40 40 M0 0 L40 0 M0 20 L40 20 M0 40 L40 40\" fill=\"none\" stroke=\"#CCCCCC\" stroke-width=\"0.5\"/>\n",
+                "<path d=\"M10 30 L30 30 L30 18 M10 18 L10 30\" fill=\"none\" stroke=\"{stroke}\" stroke-width=\"4\"/>\n",
+                "<path d=\"M20 30 L20.39 29.5 L20.78 29 L21.17 28.5 L21.55 28 L21.93 27.5 L22.3 27 L22.65 26.5 L23 26 L23.33 25.5 L23.65 25 L23.96 24.5 L24.24 24 L24.51 23.5 L24.76 23 L24.99 22.5 L25.2 22 L25.38 21.5 L25.54 21 L25.68 20.5 L25.8 20 L25.88 19.5 L25.95 19 L25.99 18.5 L26 18\" fill=\"none\" stroke=\"#94b4dd\" stroke-width=\"2\"/>\n",
+                "<path d=\"M20 30 L19.61 29.5 L19.22 29 L18.83 28.5 L18.45 28 L18.07 27.5 L17.7 27 L17.35 26.5 L17 26 L16.67 25.5 L16.35 25 L16.04 24.5 L15.76 24 L15.49 23.5 L15.24 23 L15.01 22.5 L14.8 22 L14.62 21.5 L14.46 21 L14.32 20.5 L14.2 20 L14.12 19.5 L14.05 19 L14.01 18.5 L14 18\" fill=\"none\" stroke=\"#94b4dd\" stroke-width=\"2\"/>\n",
+                "<path d=\"M20 30 L23 30 A3 3 0 0 1 17 30 A3 3 0 0 1 23 30\" fill=\"#555\"/>\n",
+                "<path d=\"M20 18 L23 18 A3 3 0 0 1 17 18 A3 3 0 0 1 23 18\" fill=\"#e44\"/>\n",
+                "</svg>\n",
+            ),
+            stroke = stroke
+        )
+    }
+
+    fn short_tube(audio: &mut Audio<OfflineSynth>) -> Tube {
+        Tube::new(
+            audio,
+            Point { x: 20.0, y: 30.0 },
+            Vector2::up(),
+            12.0,
+            SPEED,
+        )
+    }
+
+    #[test]
+    fn svg_of_grid_and_tube() {
+        let mut audio = offline();
+        let tube = short_tube(&mut audio);
+        assert_eq!(svg_of(&tube), golden_svg("#0ff"));
+    }
+
+    #[test]
+    fn svg_of_selected_tube() {
+        let mut audio = offline();
+        let mut tube = short_tube(&mut audio);
+        tube.selected = true;
+        assert_eq!(svg_of(&tube), golden_svg("#f0f"));
+    }
+
+    #[test]
+    fn new_tubes_stand_in_free_slots_inside_the_canvas() {
+        let mut audio = offline();
+        let mut tubes = Vec::new();
+        // Room for three columns and two rows of tubes.
+        for _ in 0..7 {
+            let from = free_slot(&tubes, 170.0, 140.0);
+            assert!(from.x > 0.0 && from.x < 170.0 && from.y > 0.0 && from.y < 140.0);
+            tubes.push(Tube::new(&mut audio, from, Vector2::up(), 20.0, SPEED));
+        }
+        let slots = tubes
+            .iter()
+            .map(|t| (t.from.x, t.from.y))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            slots,
+            vec![
+                (50.0, 120.0),
+                (100.0, 120.0),
+                (150.0, 120.0),
+                (50.0, 70.0),
+                (100.0, 70.0),
+                (150.0, 70.0),
+                (50.0, 120.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn new_tubes_avoid_long_tubes() {
+        let mut audio = offline();
+        let long = Point { x: 50.0, y: 120.0 };
+        let tubes = vec![Tube::new(&mut audio, long, Vector2::up(), 100.0, SPEED)];
+        assert_eq!(
+            free_slot(&tubes, 120.0, 140.0),
+            Point { x: 100.0, y: 120.0 }
+        );
+        assert_eq!(free_slot(&[], 120.0, 140.0), long);
+    }
+
+    #[test]
+    fn loaded_modes_stay_within_the_harmonics() {
+        let mut audio = offline();
+        let mut layout = tube(&mut audio, 440.0).to_layout();
+        layout.mode = ::std::u32::MAX;
+        let closed = Tube::from_layout(&mut audio, &layout, SPEED);
+        assert_eq!(closed.mode, HARMONICS as u32 - 1);
+        assert_eq!(closed.step_mode(true), HARMONICS as u32);
+        layout.open = true;
+        let open = Tube::from_layout(&mut audio, &layout, SPEED);
+        assert_eq!(open.mode, HARMONICS as u32);
+        assert_eq!(open.step_mode(true), HARMONICS as u32);
+        layout.mode = 0;
+        assert_eq!(Tube::from_layout(&mut audio, &layout, SPEED).mode, 1);
+    }
+
+    #[test]
+    fn length_stays_positive() {
+        for &open in [false, true].iter() {
+            let length = Tube::length(440.0, DEFAULT_RADIUS, open, SPEED);
+            let frequency = Tube::frequency_for(length, DEFAULT_RADIUS, open, SPEED);
+            assert!((frequency - 440.0).abs() < 1e-9);
+            // The end corrections of a wide tube exceed a short wavelength.
+            assert_eq!(Tube::length(20000.0, 0.05, open, SPEED), MIN_LENGTH);
+        }
+    }
+
+    #[test]
+    fn renders_silence_until_started() {
+        let mut audio = offline();
+        let _tube = tube(&mut audio, 440.0);
+        let samples = audio.synth.render(1000);
+        assert_eq!(samples.len(), 1000);
+        assert_eq!(peak(&samples), 0.0);
+    }
+
+    #[test]
+    fn renders_tube_at_its_fundamental() {
+        let mut audio = offline();
+        let mut tube = tube(&mut audio, 440.0);
+        tube.source.start(&mut audio);
+        let samples = audio.synth.render(SAMPLE_RATE as usize);
+        assert_eq!(samples.len(), SAMPLE_RATE as usize);
+        assert_eq!(audio.synth.current_time(), 1.0);
+
+        // Harmonics are scaled to sum to one, so the waveform never
+        // exceeds the envelope, which sustains at 0.8 after peaking at 1.
+        let attack = peak(&samples[..(0.05 * SAMPLE_RATE) as usize]);
+        let sustained = peak(&samples[SAMPLE_RATE as usize / 2..]);
+        assert!(
+            attack <= 1.0 && sustained <= 0.8,
+            "{} {}",
+            attack,
+            sustained
+        );
+        assert!(
+            (sustained / attack - 0.8).abs() < 0.05,
+            "{} {}",
+            attack,
+            sustained
+        );
+
+        // The odd harmonics of a closed tube cross zero once a period.
+        let crossings = samples
+            .windows(2)
+            .filter(|w| w[0] <= 0.0 && w[1] > 0.0)
+            .count();
+        assert!((crossings as i64 - 440).abs() <= 1, "{}", crossings);
+    }
+
+    #[test]
+    fn renders_release_to_silence() {
+        let mut audio = offline();
+        let mut tube = tube(&mut audio, 220.0);
+        tube.source.start(&mut audio);
+        audio.synth.render(4410);
+        tube.source.pause(&mut audio);
+        let release = (tube.envelope.release * SAMPLE_RATE) as usize;
+        assert!(peak(&audio.synth.render(release)) > 0.0);
+        assert_eq!(peak(&audio.synth.render(100)), 0.0);
+    }
+
+    fn playing(audio: &mut Audio<OfflineSynth>, frequencies: &[f64]) -> Vec<Tube> {
+        frequencies
+            .iter()
+            .map(|&frequency| {
+                let mut tube = tube(audio, frequency);
+                tube.source.start(audio);
+                tube
+            })
+            .collect()
+    }
+
+    fn rms(samples: &[f32]) -> f64 {
+        let sum = samples.iter().map(|&s| s as f64 * s as f64).sum::<f64>();
+        (sum / samples.len() as f64).sqrt()
+    }
+
+    #[test]
+    fn renders_exported_tubes() {
+        let mut audio = offline();
+        let tubes = playing(&mut audio, &[440.0]);
+        let samples = render_tubes(&tubes, SPEED, 0.5);
+        // Half a second and the release, as rendered when this was written.
+        assert_eq!(samples.len(), 22050 + 6615);
+        assert!((peak(&samples) - 0.4578).abs() < 1e-3);
+        assert!((rms(&samples) - 0.2867).abs() < 1e-3);
+        let start = [0.0, 0.000532, 0.001354, 0.002076, 0.002463, 0.0026];
+        for (&actual, &expected) in samples.iter().zip(&start) {
+            assert!((actual - expected).abs() < 1e-5, "{:?}", &samples[..6]);
+        }
+    }
+
+    #[test]
+    fn renders_sequences_up_to_a_limit() {
+        let mut audio = offline();
+        let tubes = vec![tube(&mut audio, 440.0)];
+        let note = |start, duration| NoteEvent {
+            tube: tubes[0].id,
+            start,
+            duration,
+            velocity: 1.0,
+        };
+        // Half a second per beat.
+        let mut sequence = Sequence::default();
+        sequence.add(note(0.0, 1.0));
+        let short = render_sequence(&tubes, &sequence, SPEED, 10.0);
+        let release = tubes[0].envelope.release;
+        assert!(short.len() >= frames(0.5 + release));
+        assert!(short.len() <= frames(0.5 + release + 2.0 * SCHEDULE_AHEAD));
+
+        // Notes that would sound, or start, for hours are cut off.
+        sequence.add(note(1e9, 1.0));
+        assert_eq!(
+            render_sequence(&tubes, &sequence, SPEED, 2.0).len(),
+            frames(2.0)
+        );
+        sequence.events.remove(1);
+        sequence.add(note(0.0, 1e9));
+        assert_eq!(
+            render_sequence(&tubes, &sequence, SPEED, 2.0).len(),
+            frames(2.0)
+        );
+    }
+
+    #[test]
+    fn exported_mixes_have_headroom() {
+        let mut audio = offline();
+        let chord = [220.0, 247.0, 262.0, 294.0, 330.0, 349.0, 392.0, 440.0];
+        let mut mix = render_tubes(&playing(&mut audio, &chord), SPEED, 0.5);
+        assert!(peak(&mix) > 1.0);
+        wav::limit(&mut mix, EXPORT_PEAK);
+        assert!((peak(&mix) - EXPORT_PEAK).abs() < 1e-6);
+
+        // A single tube is quiet enough to keep its level.
+        let mut one = render_tubes(&playing(&mut audio, &[440.0]), SPEED, 0.5);
+        let before = one.clone();
+        wav::limit(&mut one, EXPORT_PEAK);
+        assert_eq!(one, before);
+    }
+
+    /// The state an `Instrument` borrows, owned by a test.
+    struct Fixture {
+        tubes: Vec<Tube>,
+        audio: Audio<OfflineSynth>,
+        sequence: Sequence,
+        playback: Option<Playback>,
+        recorder: Option<Recorder>,
+        sounding: Vec<(AudioSource, f64)>,
+        next_id: u32,
+    }
+
+    impl Fixture {
+        /// Three tubes an octave apart in a row, the middle one playing.
+        fn new() -> Fixture {
+            let mut audio = offline();
+            let mut tubes = Vec::new();
+            for (i, &frequency) in [220.0, 440.0, 880.0].iter().enumerate() {
+                let mut tube = tube(&mut audio, frequency);
+                tube.id = i as u32 + 1;
+                tube.from = Point {
+                    x: 20.0 * i as f64,
+                    y: 0.0,
+                };
+                tubes.push(tube);
+            }
+            tubes[1].source.start(&mut audio);
+            Fixture {
+                tubes,
+                audio,
+                sequence: Sequence::default(),
+                playback: None,
+                recorder: None,
+                sounding: Vec::new(),
+                next_id: 3,
+            }
+        }
+
+        fn apply(&mut self, edit: Edit) -> Edit {
+            Instrument {
+                tubes: &mut self.tubes,
+                audio: &mut self.audio,
+                sequence: &mut self.sequence,
+                playback: &mut self.playback,
+                recorder: &mut self.recorder,
+                sounding: &mut self.sounding,
+                next_id: &mut self.next_id,
+                speed: SPEED,
+            }
+            .apply(edit)
+        }
+
+        /// Everything an edit can change, as JSON.
+        fn state(&self) -> String {
+            let tubes = self
+                .tubes
+                .iter()
+                .map(|t| (t.to_layout(), t.source.frequency))
+                .collect::<Vec<_>>();
+            serde_json::to_string(&(tubes, &self.sequence)).unwrap()
+        }
+
+        /// Applies `edit`, then undoes, redoes and undoes it again,
+        /// checking each step lands on the state before or after it.
+        fn assert_reverts(&mut self, edit: Edit) {
+            let before = self.state();
+            let undo = self.apply(edit);
+            let after = self.state();
+            assert_ne!(before, after, "{:?}", undo);
+            let redo = self.apply(undo);
+            assert_eq!(self.state(), before, "{:?}", redo);
+            let undo = self.apply(redo);
+            assert_eq!(self.state(), after, "{:?}", undo);
+            self.apply(undo);
+            assert_eq!(self.state(), before);
+        }
+    }
+
+    #[test]
+    fn every_edit_reverts() {
+        let mut fixture = Fixture::new();
+        let mut layout = fixture.tubes[0].to_layout();
+        layout.id = 0;
+        layout.from = Point { x: 60.0, y: 0.0 };
+        let mut sequence = Sequence::default();
+        sequence.add(NoteEvent {
+            tube: 2,
+            start: 0.0,
+            duration: 1.0,
+            velocity: 1.0,
+        });
+        let length = fixture.tubes[1].length;
+        let edits = vec![
+            Edit::Insert {
+                index: 1,
+                tube: layout,
+            },
+            Edit::Remove { index: 0 },
+            Edit::Remove { index: 1 },
+            Edit::SetLength {
+                index: 1,
+                length: length / 2.0,
+            },
+            Edit::Move {
+                index: 2,
+                from: Point { x: 40.0, y: 20.0 },
+            },
+            Edit::SetOpen {
+                index: 0,
+                open: true,
+            },
+            Edit::SetMode { index: 1, mode: 3 },
+            Edit::SetPlaying {
+                index: 0,
+                playing: true,
+            },
+            Edit::SetPlaying {
+                index: 1,
+                playing: false,
+            },
+            Edit::SetSequence(sequence),
+        ];
+        for edit in edits {
+            fixture.assert_reverts(edit);
+        }
+        // A tube inserted without an id keeps the one it was given when
+        // the insertion is redone.
+        assert_eq!(fixture.next_id, 4);
+    }
+
+    #[test]
+    fn groups_revert_in_reverse_order() {
+        let mut fixture = Fixture::new();
+        let length = fixture.tubes[0].length;
+        let group = Edit::Group(vec![
+            Edit::SetLength {
+                index: 0,
+                length: length * 2.0,
+            },
+            Edit::Remove { index: 0 },
+            Edit::SetOpen {
+                index: 0,
+                open: true,
+            },
+        ]);
+        match fixture.apply(group.clone()) {
+            Edit::Group(inverses) => match inverses[..] {
+                [Edit::SetOpen { index: 0, .. }, Edit::Insert { index: 0, .. }, Edit::SetLength { index: 0, .. }] =>
+                    {}
+                _ => panic!("{:?}", inverses),
+            },
+            inverse => panic!("{:?}", inverse),
+        }
+        let mut fixture = Fixture::new();
+        fixture.assert_reverts(group);
+    }
+
+    #[test]
+    fn removals_at_reverse_indices_revert() {
+        // Deleting a selection removes from the back so the indices stay
+        // valid, and undoing it must put each tube back in its place.
+        let mut fixture = Fixture::new();
+        let before = fixture.state();
+        let undo = fixture.apply(Edit::Group(vec![
+            Edit::Remove { index: 2 },
+            Edit::Remove { index: 0 },
+        ]));
+        assert_eq!(
+            fixture.tubes.iter().map(|t| t.id).collect::<Vec<_>>(),
+            vec![2]
+        );
+        fixture.apply(undo);
+        assert_eq!(fixture.state(), before);
+        assert_eq!(
+            fixture.tubes.iter().map(|t| t.id).collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+        fixture.assert_reverts(Edit::Group(vec![
+            Edit::Remove { index: 2 },
+            Edit::Remove { index: 1 },
+            Edit::Remove { index: 0 },
+        ]));
+    }
+}
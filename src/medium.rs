@@ -0,0 +1,178 @@
+/// Molar gas constant, J/(mol K).
+const GAS_CONSTANT: f64 = 8.314_462_618;
+const ZERO_CELSIUS: f64 = 273.15;
+/// Standard atmospheric pressure, kPa.
+const ATMOSPHERE: f64 = 101.325;
+/// Adiabatic index and molar mass (kg/mol) of water vapour.
+const WATER_VAPOUR: (f64, f64) = (1.330, 0.018_015);
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Gas {
+    Air,
+    Helium,
+    CarbonDioxide,
+}
+
+impl Gas {
+    pub fn from_name(name: &str) -> Option<Gas> {
+        match &*name.to_lowercase() {
+            "air" => Some(Gas::Air),
+            "helium" | "he" => Some(Gas::Helium),
+            "co2" | "carbon dioxide" => Some(Gas::CarbonDioxide),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Gas::Air => "air",
+            Gas::Helium => "helium",
+            Gas::CarbonDioxide => "CO2",
+        }
+    }
+
+    /// Adiabatic index and molar mass (kg/mol).
+    fn properties(self) -> (f64, f64) {
+        match self {
+            Gas::Air => (1.400, 0.028_964_5),
+            Gas::Helium => (1.660, 0.004_002_6),
+            Gas::CarbonDioxide => (1.289, 0.044_01),
+        }
+    }
+}
+
+/// The gas filling the tubes. Humidity is relative, in percent, and only
+/// affects air.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Medium {
+    pub gas: Gas,
+    pub temperature: f64,
+    pub humidity: f64,
+}
+
+impl Default for Medium {
+    fn default() -> Medium {
+        Medium {
+            gas: Gas::Air,
+            temperature: 20.0,
+            humidity: 0.0,
+        }
+    }
+}
+
+impl Medium {
+    /// Checks that the temperature is above absolute zero and the humidity
+    /// a percentage.
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.temperature.is_finite() || self.temperature <= -ZERO_CELSIUS {
+            return Err(format!(
+                "temperature {}°C is not physical",
+                self.temperature
+            ));
+        }
+        if !self.humidity.is_finite() || self.humidity < 0.0 || self.humidity > 100.0 {
+            return Err(format!("humidity {}% is not a percentage", self.humidity));
+        }
+        Ok(())
+    }
+
+    /// Speed of sound in m/s, treating the gas as ideal: c = sqrt(γRT/M).
+    pub fn speed_of_sound(&self) -> f64 {
+        let (mut gamma, mut molar_mass) = self.gas.properties();
+        if self.gas == Gas::Air && self.humidity > 0.0 {
+            // Mix in water vapour by mole fraction, combining heat capacities
+            // rather than adiabatic indices. Above boiling, the saturation
+            // pressure passes an atmosphere and the air can be all vapour.
+            let x = (self.humidity.min(100.0) / 100.0 * saturation_pressure(self.temperature)
+                / ATMOSPHERE)
+                .min(1.0);
+            let (gamma_w, molar_mass_w) = WATER_VAPOUR;
+            gamma = 1.0 + 1.0 / ((1.0 - x) / (gamma - 1.0) + x / (gamma_w - 1.0));
+            molar_mass = (1.0 - x) * molar_mass + x * molar_mass_w;
+        }
+        let t = self.temperature + ZERO_CELSIUS;
+        (gamma * GAS_CONSTANT * t / molar_mass).sqrt()
+    }
+}
+
+/// Saturation vapour pressure of water in kPa (Buck equation).
+fn saturation_pressure(temperature: f64) -> f64 {
+    0.611_21 * ((18.678 - temperature / 234.5) * (temperature / (257.14 + temperature))).exp()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn speed_in_air_at_room_temperature() {
+        let speed = Medium::default().speed_of_sound();
+        assert!((speed - 343.2).abs() < 0.3, "{}", speed);
+    }
+
+    fn air(temperature: f64, humidity: f64) -> Medium {
+        Medium {
+            gas: Gas::Air,
+            temperature,
+            humidity,
+        }
+    }
+
+    #[test]
+    fn humid_air_is_faster() {
+        let dry = air(20.0, 0.0).speed_of_sound();
+        let humid = air(20.0, 100.0).speed_of_sound();
+        assert!(humid > dry && (humid - 344.5).abs() < 0.3, "{}", humid);
+    }
+
+    #[test]
+    fn air_above_boiling_is_at_most_steam() {
+        let steam = air(200.0, 100.0).speed_of_sound();
+        assert!((steam - 538.9).abs() < 0.3, "{}", steam);
+        for &(temperature, humidity) in &[(200.0, 30.0), (120.0, 100.0), (100.0, 50.0)] {
+            let speed = air(temperature, humidity).speed_of_sound();
+            assert!(speed.is_finite() && speed <= steam, "{}", speed);
+        }
+    }
+
+    #[test]
+    fn speed_in_other_gases() {
+        let gas = |gas| Medium {
+            gas,
+            ..Medium::default()
+        };
+        let helium = gas(Gas::Helium).speed_of_sound();
+        assert!((helium - 1005.4).abs() < 0.3, "{}", helium);
+        let co2 = gas(Gas::CarbonDioxide).speed_of_sound();
+        assert!((co2 - 267.2).abs() < 0.3, "{}", co2);
+        // Humidity only affects air.
+        let humid = Medium {
+            humidity: 100.0,
+            ..gas(Gas::Helium)
+        };
+        assert_eq!(humid.speed_of_sound(), helium);
+    }
+
+    #[test]
+    fn rejects_non_physical_media() {
+        assert!(Medium::default().validate().is_ok());
+        for &(temperature, humidity) in [
+            (-273.15, 0.0),
+            (-300.0, 0.0),
+            (::std::f64::NAN, 0.0),
+            (::std::f64::INFINITY, 0.0),
+            (20.0, -1.0),
+            (20.0, 101.0),
+            (20.0, ::std::f64::NAN),
+        ]
+        .iter()
+        {
+            let medium = Medium {
+                gas: Gas::Air,
+                temperature,
+                humidity,
+            };
+            assert!(medium.validate().is_err(), "{:?}", medium);
+        }
+    }
+}
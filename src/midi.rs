@@ -0,0 +1,815 @@
+use std::collections::{HashMap, VecDeque};
+
+use piano;
+
+/// Quarter notes per minute when a file sets no tempo.
+const DEFAULT_TEMPO: f64 = 120.0;
+
+/// A note read from a MIDI file. Times are in beats at the file's first
+/// tempo.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct MidiNote {
+    pub channel: u8,
+    /// MIDI note number; 69 is A4.
+    pub number: u8,
+    pub velocity: u8,
+    pub start: f64,
+    pub duration: f64,
+}
+
+/// The notes of a Standard MIDI File, from all of its tracks.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MidiFile {
+    pub format: u16,
+    /// Quarter notes per minute, from the file's first tempo event. As a
+    /// sequence has a single tempo, later tempo changes are folded into the
+    /// note times instead.
+    pub tempo: f64,
+    /// Ordered by start.
+    pub notes: Vec<MidiNote>,
+}
+
+/// The piano key of MIDI note `number`, if it is on the keyboard.
+pub fn to_key(number: u8) -> Option<usize> {
+    let key = number as usize;
+    if key < 21 || key > 108 {
+        None
+    } else {
+        Some(key - 20)
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Reader<'a> {
+        Reader { data, pos: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if self.data.len() - self.pos < n {
+            return Err("midi: unexpected end of data".to_string());
+        }
+        let bytes = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.data.get(self.pos).cloned()
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        let b = self.bytes(2)?;
+        Ok((b[0] as u16) << 8 | b[1] as u16)
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let b = self.bytes(4)?;
+        Ok(b.iter().fold(0, |n, &b| n << 8 | b as u32))
+    }
+
+    /// A variable-length quantity: seven bits per byte, most significant
+    /// first, with the top bit set on all but the last byte.
+    fn vlq(&mut self) -> Result<u32, String> {
+        let mut value = 0;
+        for _ in 0..4 {
+            let b = self.u8()?;
+            value = value << 7 | (b & 0x7f) as u32;
+            if b & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err("midi: variable-length quantity is too long".to_string())
+    }
+
+    /// The next chunk's type and contents.
+    fn chunk(&mut self) -> Result<(&'a [u8], Reader<'a>), String> {
+        let kind = self.bytes(4)?;
+        let length = self.u32()? as usize;
+        Ok((kind, Reader::new(self.bytes(length)?)))
+    }
+}
+
+/// Parses a format 0 or 1 Standard MIDI File.
+pub fn parse(data: &[u8]) -> Result<MidiFile, String> {
+    let mut reader = Reader::new(data);
+    let (kind, mut header) = reader.chunk()?;
+    if kind != b"MThd" {
+        return Err("midi: not a Standard MIDI File".to_string());
+    }
+    let format = header.u16()?;
+    let tracks = header.u16()?;
+    let division = header.u16()?;
+    if format > 1 {
+        return Err(format!("midi: format {} files are not supported", format));
+    }
+    if division & 0x8000 != 0 || division == 0 {
+        return Err("midi: SMPTE time division is not supported".to_string());
+    }
+
+    let mut tempos = Vec::new();
+    let mut spans = Vec::new();
+    let mut read = 0;
+    while read < tracks && !reader.is_empty() {
+        let (kind, track) = reader.chunk()?;
+        // Unknown chunks are to be skipped.
+        if kind == b"MTrk" {
+            parse_track(track, &mut tempos, &mut spans)?;
+            read += 1;
+        }
+    }
+
+    // Tempo events may be in any track, but apply to all of them.
+    tempos.sort_by_key(|&(tick, _)| tick);
+    let quarter = tempos
+        .first()
+        .map_or(60.0 / DEFAULT_TEMPO, |&(_, quarter)| quarter);
+    let beat = |tick| beats(&tempos, division as f64, quarter, tick);
+    let mut notes = spans
+        .iter()
+        .map(|span| {
+            let start = beat(span.start);
+            MidiNote {
+                channel: span.channel,
+                number: span.number,
+                velocity: span.velocity,
+                start,
+                duration: beat(span.end) - start,
+            }
+        })
+        .collect::<Vec<_>>();
+    notes.sort_by(|a: &MidiNote, b: &MidiNote| a.start.partial_cmp(&b.start).unwrap());
+    Ok(MidiFile {
+        format,
+        tempo: 60.0 / quarter,
+        notes,
+    })
+}
+
+/// A note as read from a track, timed in ticks.
+struct Span {
+    channel: u8,
+    number: u8,
+    velocity: u8,
+    start: u64,
+    end: u64,
+}
+
+/// The time of `ticks` in beats lasting `beat` seconds, given the tempo
+/// changes as (tick, seconds per quarter note) in order of tick.
+fn beats(tempos: &[(u64, f64)], division: f64, beat: f64, ticks: u64) -> f64 {
+    let mut beats = 0.0;
+    let mut at = 0;
+    let mut quarter = 60.0 / DEFAULT_TEMPO;
+    for &(tick, next) in tempos.iter().take_while(|&&(tick, _)| tick < ticks) {
+        beats += (tick - at) as f64 / division * (quarter / beat);
+        at = tick;
+        quarter = next;
+    }
+    beats + (ticks - at) as f64 / division * (quarter / beat)
+}
+
+fn parse_track(
+    mut track: Reader,
+    tempos: &mut Vec<(u64, f64)>,
+    spans: &mut Vec<Span>,
+) -> Result<(), String> {
+    let mut ticks = 0u64;
+    let mut status = None;
+    // Start tick and velocity of each sounding (channel, note), oldest first.
+    let mut held: HashMap<(u8, u8), VecDeque<(u64, u8)>> = HashMap::new();
+    let mut close = |queue: &mut VecDeque<(u64, u8)>, channel, number, end: u64| {
+        if let Some((start, velocity)) = queue.pop_front() {
+            spans.push(Span {
+                channel,
+                number,
+                velocity,
+                start,
+                end,
+            });
+        }
+    };
+
+    while !track.is_empty() {
+        ticks += track.vlq()? as u64;
+        // Without a status byte, the last channel message's status repeats,
+        // unless a meta or sysex event has come since.
+        let byte = match track.peek() {
+            Some(b) if b & 0x80 != 0 => track.u8()?,
+            _ => status.ok_or_else(|| "midi: data byte without status".to_string())?,
+        };
+        match byte {
+            0xff => {
+                status = None;
+                let kind = track.u8()?;
+                let length = track.vlq()? as usize;
+                let data = track.bytes(length)?;
+                match kind {
+                    0x2f => break,
+                    0x51 if length == 3 => {
+                        let micros = data.iter().fold(0, |n, &b| n << 8 | b as u32);
+                        if micros > 0 {
+                            tempos.push((ticks, micros as f64 / 1e6));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            0xf0 | 0xf7 => {
+                status = None;
+                let length = track.vlq()? as usize;
+                track.bytes(length)?;
+            }
+            0x80..=0xef => {
+                status = Some(byte);
+                let channel = byte & 0x0f;
+                match byte & 0xf0 {
+                    0x80 | 0x90 => {
+                        let number = track.u8()? & 0x7f;
+                        let velocity = track.u8()? & 0x7f;
+                        let queue = held.entry((channel, number)).or_insert_with(VecDeque::new);
+                        if byte & 0xf0 == 0x90 && velocity > 0 {
+                            queue.push_back((ticks, velocity));
+                        } else {
+                            close(queue, channel, number, ticks);
+                        }
+                    }
+                    0xc0 | 0xd0 => {
+                        track.u8()?;
+                    }
+                    _ => {
+                        track.bytes(2)?;
+                    }
+                }
+            }
+            _ => return Err(format!("midi: unexpected status byte {:#04x}", byte)),
+        }
+    }
+
+    // Notes never released end with the track.
+    for (&(channel, number), queue) in held.iter_mut() {
+        while !queue.is_empty() {
+            close(queue, channel, number, ticks);
+        }
+    }
+    Ok(())
+}
+
+/// Ticks per quarter note in exported files.
+const DIVISION: u16 = 480;
+/// How far, in cents, a full pitch bend reaches. This is the General MIDI
+/// default, which exported files also set explicitly.
+const BEND_RANGE: f64 = 200.0;
+/// The General MIDI percussion channel, which exported notes avoid.
+const DRUMS: u8 = 9;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Change {
+    /// Frequency and velocity, from 0 to 1.
+    On(f64, f64),
+    Off,
+    Retune(f64),
+}
+
+/// How long, in seconds, retunes of one source are merged into the first,
+/// so dragging a tube logs a few pitch bends rather than one per move.
+const RETUNE_INTERVAL: f64 = 0.05;
+/// How many events a performance keeps before forgetting the oldest.
+const MAX_EVENTS: usize = 100_000;
+
+/// A log of audio sources starting, stopping and changing pitch, as played
+/// by hand, that can be saved as a MIDI file.
+#[derive(Debug, Default)]
+pub struct Performance {
+    /// (time in seconds, source id, change), in order of time.
+    events: VecDeque<(f64, usize, Change)>,
+}
+
+/// A note sounding while a performance is exported.
+struct Sounding {
+    channel: u8,
+    number: u8,
+    velocity: u8,
+}
+
+impl Performance {
+    fn push(&mut self, time: f64, source: usize, change: Change) {
+        self.events.push_back((time, source, change));
+        if self.events.len() > MAX_EVENTS {
+            self.events.pop_front();
+        }
+    }
+
+    pub fn note_on(&mut self, source: usize, time: f64, frequency: f64, velocity: f64) {
+        self.push(time, source, Change::On(frequency, velocity));
+    }
+
+    pub fn note_off(&mut self, source: usize, time: f64) {
+        self.push(time, source, Change::Off);
+    }
+
+    pub fn retune(&mut self, source: usize, time: f64, frequency: f64) {
+        let last = self
+            .events
+            .iter_mut()
+            .rev()
+            .take_while(|e| time - e.0 < RETUNE_INTERVAL)
+            .find(|e| e.1 == source);
+        if let Some(&mut (_, _, ref mut change @ Change::Retune(_))) = last {
+            *change = Change::Retune(frequency);
+            return;
+        }
+        self.push(time, source, Change::Retune(frequency));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.events.clear();
+    }
+
+    /// A format 0 Standard MIDI File of the performance at 120 bpm, starting
+    /// from the first note. Every note gets a channel of its own while it
+    /// sounds, so its pitch can be bent to within a cent of the source's
+    /// without affecting other notes; notes still sounding end at `end`.
+    pub fn to_midi(&self, end: f64) -> Vec<u8> {
+        let origin = self.events.front().map_or(end, |e| e.0);
+        let tick = |time: f64| ((time - origin).max(0.0) * 2.0 * DIVISION as f64).round() as u64;
+        let mut track = Track::default();
+        // 500000 microseconds per quarter note.
+        track.event(0, &[0xff, 0x51, 0x03, 0x07, 0xa1, 0x20]);
+        for channel in (0..16).filter(|&c| c != DRUMS) {
+            // Registered parameter 0, the pitch bend range, in semitones,
+            // then the null parameter so stray data entry changes nothing.
+            let semitones = (BEND_RANGE / 100.0) as u8;
+            let rpn = [
+                (101, 0),
+                (100, 0),
+                (6, semitones),
+                (38, 0),
+                (101, 127),
+                (100, 127),
+            ];
+            for &(controller, value) in rpn.iter() {
+                track.event(0, &[0xb0 | channel, controller, value]);
+            }
+        }
+
+        let mut sounding: HashMap<usize, Sounding> = HashMap::new();
+        let mut next_channel = 0;
+        for &(time, source, change) in &self.events {
+            let ticks = tick(time);
+            let (frequency, velocity) = match change {
+                Change::On(frequency, velocity) if !sounding.contains_key(&source) => {
+                    (frequency, velocity)
+                }
+                Change::Retune(frequency) if sounding.contains_key(&source) => {
+                    let (number, bend) = pitch(frequency);
+                    let note = &sounding[&source];
+                    if number == note.number {
+                        track.event(ticks, &bend_message(note.channel, bend));
+                        continue;
+                    }
+                    (frequency, note.velocity as f64 / 127.0)
+                }
+                Change::Off => {
+                    if let Some(note) = sounding.remove(&source) {
+                        track.event(ticks, &[0x80 | note.channel, note.number, 0]);
+                    }
+                    continue;
+                }
+                _ => continue,
+            };
+            // A new note, or one retuned too far to bend: (re)start it on a
+            // free channel, or with every channel busy, share the next one.
+            let channel = match sounding.remove(&source) {
+                Some(note) => {
+                    track.event(ticks, &[0x80 | note.channel, note.number, 0]);
+                    note.channel
+                }
+                None => {
+                    let free = (0..16)
+                        .map(|i| (next_channel + i) % 16)
+                        .filter(|&c| c != DRUMS)
+                        .find(|&c| sounding.values().all(|n| n.channel != c));
+                    let channel = free.unwrap_or(if next_channel == DRUMS {
+                        DRUMS + 1
+                    } else {
+                        next_channel
+                    });
+                    next_channel = (channel + 1) % 16;
+                    channel
+                }
+            };
+            let (number, bend) = pitch(frequency);
+            track.event(ticks, &bend_message(channel, bend));
+            // Velocity 0 would end the note instead.
+            let velocity = ((velocity * 127.0).round() as u8).max(1).min(127);
+            track.event(ticks, &[0x90 | channel, number, velocity]);
+            sounding.insert(
+                source,
+                Sounding {
+                    channel,
+                    number,
+                    velocity,
+                },
+            );
+        }
+        let ticks = tick(end);
+        for note in sounding.values() {
+            track.event(ticks, &[0x80 | note.channel, note.number, 0]);
+        }
+        track.event(ticks, &[0xff, 0x2f, 0x00]);
+
+        let mut data = Vec::new();
+        data.extend_from_slice(b"MThd");
+        data.extend_from_slice(&[0, 0, 0, 6, 0, 0, 0, 1]);
+        data.extend_from_slice(&[(DIVISION >> 8) as u8, DIVISION as u8]);
+        data.extend_from_slice(b"MTrk");
+        let length = track.data.len() as u32;
+        data.extend_from_slice(&[
+            (length >> 24) as u8,
+            (length >> 16) as u8,
+            (length >> 8) as u8,
+            length as u8,
+        ]);
+        data.extend_from_slice(&track.data);
+        data
+    }
+}
+
+/// The MIDI note nearest to `frequency` and the 14-bit pitch bend that
+/// makes up the difference.
+fn pitch(frequency: f64) -> (u8, u16) {
+    let key = piano::to_key(frequency);
+    let cents = piano::cents(frequency, piano::frequency(key));
+    let bend = 8192.0 + cents / BEND_RANGE * 8192.0;
+    ((key + 20) as u8, bend.round().max(0.0).min(16383.0) as u16)
+}
+
+fn bend_message(channel: u8, bend: u16) -> [u8; 3] {
+    [0xe0 | channel, (bend & 0x7f) as u8, (bend >> 7) as u8]
+}
+
+#[derive(Default)]
+struct Track {
+    data: Vec<u8>,
+    ticks: u64,
+}
+
+impl Track {
+    /// Appends `message` at absolute time `ticks`, which must not be
+    /// earlier than the previous event's.
+    fn event(&mut self, ticks: u64, message: &[u8]) {
+        let delta = ticks.saturating_sub(self.ticks);
+        self.ticks = self.ticks.max(ticks);
+        write_vlq(&mut self.data, delta as u32);
+        self.data.extend_from_slice(message);
+    }
+}
+
+fn write_vlq(data: &mut Vec<u8>, mut value: u32) {
+    let mut bytes = vec![(value & 0x7f) as u8];
+    value >>= 7;
+    while value > 0 {
+        bytes.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.reverse();
+    data.extend_from_slice(&bytes);
+}
+
+/// What a message from a MIDI controller asks the instrument to do.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Action {
+    NoteOn {
+        number: u8,
+        velocity: u8,
+    },
+    NoteOff {
+        number: u8,
+    },
+    /// Bends every sounding note by this many cents.
+    Bend(f64),
+}
+
+/// Turns live messages from a MIDI controller, on any channel, into
+/// actions, holding released notes while the sustain pedal is down.
+#[derive(Debug, Default)]
+pub struct Controller {
+    pedal: bool,
+    /// Notes whose keys are down.
+    held: Vec<u8>,
+    /// Notes released while the pedal was down, still sounding.
+    sustained: Vec<u8>,
+}
+
+impl Controller {
+    /// The actions of one complete message, as delivered by Web MIDI.
+    /// System messages, such as clock ticks, have none.
+    pub fn message(&mut self, data: &[u8]) -> Result<Vec<Action>, String> {
+        let status = match data.first() {
+            Some(&status) if status >= 0x80 => status,
+            Some(&byte) => return Err(format!("expected status byte, found {:#04x}", byte)),
+            None => return Err(String::from("empty message")),
+        };
+        let length = match status & 0xf0 {
+            0xf0 => return Ok(Vec::new()),
+            0xc0 | 0xd0 => 2,
+            _ => 3,
+        };
+        if data.len() < length {
+            return Err(format!("message {:#04x} is too short", status));
+        }
+        let mut actions = Vec::new();
+        match (status & 0xf0, data[1], data.get(2).cloned().unwrap_or(0)) {
+            (0x90, number, velocity) if velocity > 0 => {
+                // Striking a key that is still sustained restarts its note.
+                if let Some(i) = self.sustained.iter().position(|&n| n == number) {
+                    self.sustained.remove(i);
+                    actions.push(Action::NoteOff { number });
+                }
+                if !self.held.contains(&number) {
+                    self.held.push(number);
+                }
+                actions.push(Action::NoteOn { number, velocity });
+            }
+            (0x80, number, _) | (0x90, number, _) => {
+                if let Some(i) = self.held.iter().position(|&n| n == number) {
+                    self.held.remove(i);
+                    if self.pedal {
+                        self.sustained.push(number);
+                    } else {
+                        actions.push(Action::NoteOff { number });
+                    }
+                }
+            }
+            (0xb0, 64, value) => {
+                self.pedal = value >= 64;
+                if !self.pedal {
+                    for number in self.sustained.drain(..) {
+                        actions.push(Action::NoteOff { number });
+                    }
+                }
+            }
+            // All sound off and all notes off.
+            (0xb0, 120, _) | (0xb0, 123, _) => {
+                for number in self.held.drain(..).chain(self.sustained.drain(..)) {
+                    actions.push(Action::NoteOff { number });
+                }
+            }
+            (0xe0, lsb, msb) => {
+                let value = ((msb as u16) << 7 | lsb as u16) as f64;
+                actions.push(Action::Bend((value - 8192.0) / 8192.0 * BEND_RANGE));
+            }
+            _ => {}
+        }
+        Ok(actions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    /// (number, start, duration) of each note.
+    fn timings(file: &MidiFile) -> Vec<(u8, f64, f64)> {
+        file.notes
+            .iter()
+            .map(|n| (n.number, n.start, n.duration))
+            .collect()
+    }
+
+    fn assert_timings(file: &MidiFile, expected: &[(u8, f64, f64)]) {
+        let actual = timings(file);
+        assert_eq!(actual.len(), expected.len(), "{:?}", actual);
+        for (&(number, start, duration), &(n, s, d)) in actual.iter().zip(expected) {
+            assert!(
+                number == n && close(start, s) && close(duration, d),
+                "{:?} != {:?}",
+                actual,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn parses_format_0() {
+        let file = parse(include_bytes!("../tests/fixtures/format0.mid")).unwrap();
+        assert_eq!(file.format, 0);
+        assert_eq!(file.tempo, 120.0);
+        // The tempo doubles at beat 2, so the last note is half as long in
+        // beats of the first tempo.
+        assert_timings(
+            &file,
+            &[
+                (60, 0.0, 1.0),
+                (64, 0.0, 2.0),
+                (67, 1.0, 1.0),
+                (72, 2.0, 0.5),
+            ],
+        );
+        let velocities = file.notes.iter().map(|n| n.velocity).collect::<Vec<_>>();
+        assert_eq!(velocities, vec![100, 90, 80, 127]);
+    }
+
+    #[test]
+    fn parses_format_1() {
+        let file = parse(include_bytes!("../tests/fixtures/format1.mid")).unwrap();
+        assert_eq!(file.format, 1);
+        assert!(close(file.tempo, 100.0));
+        // The conductor track's tempo change applies to the other tracks.
+        assert_timings(
+            &file,
+            &[
+                (60, 0.0, 1.0),
+                (62, 0.0, 1.0),
+                (64, 2.0, 1.0),
+                (67, 2.5, 0.5),
+            ],
+        );
+        let channels = file.notes.iter().map(|n| n.channel).collect::<Vec<_>>();
+        assert_eq!(channels, vec![1, 1, 1, 2]);
+    }
+
+    fn track(events: &[u8]) -> Vec<u8> {
+        let mut data = b"MThd\0\0\0\x06\0\0\0\x01\0\x60MTrk".to_vec();
+        data.extend_from_slice(&[0, 0, 0, events.len() as u8]);
+        data.extend_from_slice(events);
+        data
+    }
+
+    #[test]
+    fn meta_and_sysex_events_clear_running_status() {
+        let meta = track(&[0, 0x90, 60, 100, 0, 0xff, 0x01, 0, 0x60, 60, 0]);
+        assert!(parse(&meta).is_err());
+        let sysex = track(&[0, 0x90, 60, 100, 0, 0xf0, 1, 0xf7, 0x60, 60, 0]);
+        assert!(parse(&sysex).is_err());
+        let channel = track(&[0, 0x90, 60, 100, 0, 0xb0, 7, 100, 0x60, 0x80, 60, 0]);
+        assert_timings(&parse(&channel).unwrap(), &[(60, 0.0, 1.0)]);
+    }
+
+    #[test]
+    fn exports_a_performance() {
+        let mut performance = Performance::default();
+        performance.note_on(1, 10.0, piano::frequency(49), 1.0);
+        performance.note_on(2, 10.5, piano::frequency(52), 0.5);
+        performance.note_off(1, 11.0);
+        let file = parse(&performance.to_midi(12.0)).unwrap();
+        assert_eq!(file.tempo, 120.0);
+        // Two beats a second from the first note; the held note ends at
+        // `end`.
+        assert_timings(&file, &[(69, 0.0, 2.0), (72, 1.0, 3.0)]);
+        assert_eq!(file.notes[0].velocity, 127);
+        assert_eq!(file.notes[1].velocity, 64);
+        assert!(file.notes[0].channel != file.notes[1].channel);
+    }
+
+    #[test]
+    fn exports_the_bend_range_then_the_null_parameter() {
+        let data = Performance::default().to_midi(0.0);
+        let range = [0xb0, 101, 0, 0, 0xb0, 100, 0, 0, 0xb0, 6, 2, 0, 0xb0, 38, 0];
+        let null = [0, 0xb0, 101, 127, 0, 0xb0, 100, 127];
+        let at = data.windows(range.len()).position(|w| w == range).unwrap() + range.len();
+        assert_eq!(&data[at..at + null.len()], &null);
+    }
+
+    #[test]
+    fn merges_quick_retunes() {
+        let mut performance = Performance::default();
+        performance.note_on(1, 0.0, 440.0, 1.0);
+        for i in 0..10 {
+            performance.retune(1, 1.0 + i as f64 * 0.01, 441.0 + i as f64);
+            // Other sources interleaved do not split the retunes.
+            performance.retune(2, 1.0 + i as f64 * 0.01, 220.0);
+        }
+        performance.retune(1, 1.2, 460.0);
+        let retunes = performance
+            .events
+            .iter()
+            .filter(|e| e.1 == 1)
+            .map(|e| (e.0, e.2))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            retunes,
+            vec![
+                (0.0, Change::On(440.0, 1.0)),
+                (1.0, Change::Retune(445.0)),
+                (1.05, Change::Retune(450.0)),
+                (1.2, Change::Retune(460.0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn forgets_the_oldest_events() {
+        let mut performance = Performance::default();
+        for i in 0..MAX_EVENTS + 10 {
+            performance.note_on(i, i as f64, 440.0, 1.0);
+        }
+        assert_eq!(performance.events.len(), MAX_EVENTS);
+        assert_eq!(performance.events[0].1, 10);
+    }
+
+    fn on(number: u8, velocity: u8) -> Action {
+        Action::NoteOn { number, velocity }
+    }
+
+    fn off(number: u8) -> Action {
+        Action::NoteOff { number }
+    }
+
+    #[test]
+    fn sustain_holds_notes_until_pedal_up() {
+        let mut controller = Controller::default();
+        assert_eq!(controller.message(&[0x90, 60, 100]), Ok(vec![on(60, 100)]));
+        assert_eq!(controller.message(&[0xb0, 64, 127]), Ok(vec![]));
+        assert_eq!(controller.message(&[0x80, 60, 0]), Ok(vec![]));
+        // Keys still down when the pedal lifts keep sounding.
+        assert_eq!(controller.message(&[0x91, 62, 90]), Ok(vec![on(62, 90)]));
+        assert_eq!(controller.message(&[0xb0, 64, 0]), Ok(vec![off(60)]));
+        assert_eq!(controller.message(&[0x80, 62, 0]), Ok(vec![off(62)]));
+    }
+
+    #[test]
+    fn restriking_a_sustained_note_restarts_it() {
+        let mut controller = Controller::default();
+        controller.message(&[0xb0, 64, 127]).unwrap();
+        controller.message(&[0x90, 60, 100]).unwrap();
+        controller.message(&[0x80, 60, 0]).unwrap();
+        assert_eq!(
+            controller.message(&[0x90, 60, 80]),
+            Ok(vec![off(60), on(60, 80)])
+        );
+        // It is held by its key again, not the pedal.
+        assert_eq!(controller.message(&[0xb0, 64, 0]), Ok(vec![]));
+        assert_eq!(controller.message(&[0x80, 60, 0]), Ok(vec![off(60)]));
+    }
+
+    #[test]
+    fn restriking_a_held_note_does_not_hold_it_twice() {
+        let mut controller = Controller::default();
+        controller.message(&[0x90, 60, 100]).unwrap();
+        assert_eq!(controller.message(&[0x90, 60, 80]), Ok(vec![on(60, 80)]));
+        assert_eq!(controller.message(&[0x80, 60, 0]), Ok(vec![off(60)]));
+        assert_eq!(controller.message(&[0x80, 60, 0]), Ok(vec![]));
+    }
+
+    #[test]
+    fn note_on_with_velocity_0_is_note_off() {
+        let mut controller = Controller::default();
+        controller.message(&[0x90, 60, 100]).unwrap();
+        assert_eq!(controller.message(&[0x90, 60, 0]), Ok(vec![off(60)]));
+        // Nothing to release for a key that is not down.
+        assert_eq!(controller.message(&[0x90, 61, 0]), Ok(vec![]));
+    }
+
+    #[test]
+    fn pitch_bend_spans_the_bend_range() {
+        let mut controller = Controller::default();
+        let mut bend = |lsb, msb| match controller.message(&[0xe0, lsb, msb]) {
+            Ok(ref actions) if actions.len() == 1 => match actions[0] {
+                Action::Bend(cents) => cents,
+                _ => panic!("{:?}", actions),
+            },
+            actions => panic!("{:?}", actions),
+        };
+        assert_eq!(bend(0, 0), -BEND_RANGE);
+        assert_eq!(bend(0, 0x40), 0.0);
+        assert!(close(bend(0x7f, 0x7f), BEND_RANGE * 8191.0 / 8192.0));
+    }
+
+    #[test]
+    fn rejects_broken_messages_and_ignores_system_ones() {
+        let mut controller = Controller::default();
+        assert!(controller.message(&[]).is_err());
+        assert!(controller.message(&[60, 100]).is_err());
+        assert!(controller.message(&[0x90, 60]).is_err());
+        assert_eq!(controller.message(&[0xf8]), Ok(vec![]));
+    }
+
+    #[test]
+    fn ends_held_notes_with_their_track() {
+        let file = parse(&track(&[0, 0x90, 60, 100, 0x60, 0xff, 0x2f, 0])).unwrap();
+        assert_timings(&file, &[(60, 0.0, 1.0)]);
+        assert_eq!(file.tempo, DEFAULT_TEMPO);
+    }
+}
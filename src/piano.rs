@@ -1,20 +1,30 @@
-pub static NOTES: &[(usize, &str)] = &[
-    (261, "C-4"),
-    (293, "D-4"),
-    (329, "E-4"),
-    (349, "F-4"),
-    (391, "G-4"),
-    (440, "A-4"),
-    (493, "B-4"),
-    (523, "C-5"),
-];
+use std::fmt;
+
+/// Keys are numbered as on a standard piano: 1 is A0, 49 is A4 and 88 is
+/// C8.
+pub const LOWEST_KEY: usize = 1;
+pub const HIGHEST_KEY: usize = 88;
+pub const A4: usize = 49;
 
 pub fn frequency(n: usize) -> f64 {
-    (2.0f64).powf((n as f64 - 49.0) / 12.0) * 440.0
+    (2.0f64).powf((n as f64 - A4 as f64) / 12.0) * 440.0
 }
 
+/// The key nearest in pitch to `f`, clamped to the keyboard.
 pub fn to_key(f: f64) -> usize {
-    (12.0 * (f / 440.0).log2()) as usize + 49
+    let key = (12.0 * (f / 440.0).log2()).round() + A4 as f64;
+    if key.is_nan() || key < LOWEST_KEY as f64 {
+        LOWEST_KEY
+    } else if key > HIGHEST_KEY as f64 {
+        HIGHEST_KEY
+    } else {
+        key as usize
+    }
+}
+
+/// How far `f` lies above `reference`, in cents.
+pub fn cents(f: f64, reference: f64) -> f64 {
+    1200.0 * (f / reference).log2()
 }
 
 static KEYS: &[&str] = &[
@@ -32,8 +42,130 @@ static KEYS: &[&str] = &[
     "G#", // 12
 ];
 
+/// Scientific pitch name of key `n`, e.g. "A4" for key 49. Octave numbers
+/// change at C, so keys 1 to 3 (A0 to B0) sit below C1.
 pub fn human_key(n: usize) -> String {
-    let octave = n / 12;
-    ::log(&format!("{:?}", n));
-    format!("{}{}", KEYS[(n % 12) - 1], octave)
+    format!("{}{}", KEYS[(n - 1) % 12], (n + 8) / 12)
+}
+
+/// Parses a note name such as "A4", "C#5" or "Bb3" into a key number.
+pub fn parse_key(name: &str) -> Option<usize> {
+    let name = name.trim();
+    let mut chars = name.chars();
+    let semitone: i32 = match chars.next()?.to_ascii_uppercase() {
+        'C' => 0,
+        'D' => 2,
+        'E' => 4,
+        'F' => 5,
+        'G' => 7,
+        'A' => 9,
+        'B' => 11,
+        _ => return None,
+    };
+    let rest = chars.as_str();
+    let accidentals = rest.len() - rest.trim_left_matches(|c| c == '#' || c == 'b').len();
+    let (accidental, octave) = rest.split_at(accidentals);
+    let accidental = accidental
+        .chars()
+        .map(|c| if c == '#' { 1 } else { -1 })
+        .sum::<i32>();
+    let octave: i32 = octave.parse().ok()?;
+    // C4 is key 40; octaves start at C.
+    let key = 40 + (octave - 4) * 12 + semitone + accidental;
+    if key < LOWEST_KEY as i32 || key > HIGHEST_KEY as i32 {
+        return None;
+    }
+    Some(key as usize)
+}
+
+/// A pitch described as the nearest piano key and its deviation from it.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Note {
+    pub key: usize,
+    pub cents: f64,
+}
+
+impl Note {
+    pub fn nearest(f: f64) -> Note {
+        let key = to_key(f);
+        Note {
+            key,
+            cents: cents(f, frequency(key)),
+        }
+    }
+}
+
+impl fmt::Display for Note {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let cents = self.cents.round();
+        let sign = if cents < 0.0 { "\u{2212}" } else { "+" };
+        write!(f, "{} {}{}¢", human_key(self.key), sign, cents.abs())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_keys_across_octaves() {
+        assert_eq!(human_key(1), "A0");
+        assert_eq!(human_key(3), "B0");
+        assert_eq!(human_key(4), "C1");
+        assert_eq!(human_key(12), "G#1");
+        assert_eq!(human_key(40), "C4");
+        assert_eq!(human_key(49), "A4");
+        assert_eq!(human_key(88), "C8");
+    }
+
+    #[test]
+    fn to_key_rounds_to_the_nearest_key() {
+        assert_eq!(to_key(440.0), A4);
+        // A quarter tone either side of A4.
+        assert_eq!(to_key(440.0 * 2f64.powf(0.49 / 12.0)), A4);
+        assert_eq!(to_key(440.0 * 2f64.powf(0.51 / 12.0)), A4 + 1);
+        assert_eq!(to_key(440.0 * 2f64.powf(-0.49 / 12.0)), A4);
+        assert_eq!(to_key(440.0 * 2f64.powf(-0.51 / 12.0)), A4 - 1);
+    }
+
+    #[test]
+    fn to_key_clamps_to_the_keyboard() {
+        assert_eq!(to_key(1.0), LOWEST_KEY);
+        assert_eq!(to_key(0.0), LOWEST_KEY);
+        assert_eq!(to_key(-440.0), LOWEST_KEY);
+        assert_eq!(to_key(::std::f64::NAN), LOWEST_KEY);
+        assert_eq!(to_key(20_000.0), HIGHEST_KEY);
+        assert_eq!(to_key(::std::f64::INFINITY), HIGHEST_KEY);
+    }
+
+    #[test]
+    fn shows_notes_with_signed_cents() {
+        let note = |key, cents| format!("{}", Note { key, cents });
+        assert_eq!(note(49, 3.6), "A4 +4¢");
+        assert_eq!(note(49, -12.2), "A4 \u{2212}12¢");
+        assert_eq!(note(40, 0.0), "C4 +0¢");
+        // Rounding a small flat deviation gives -0, which has no sign.
+        assert_eq!(note(40, -0.4), "C4 +0¢");
+        assert_eq!(note(40, -0.0), "C4 +0¢");
+        assert_eq!(format!("{}", Note::nearest(441.0)), "A4 +4¢");
+    }
+
+    #[test]
+    fn parses_note_names() {
+        assert_eq!(parse_key("A4"), Some(49));
+        assert_eq!(parse_key(" c4 "), Some(40));
+        assert_eq!(parse_key("C#5"), Some(53));
+        assert_eq!(parse_key("Bb3"), Some(38));
+        assert_eq!(parse_key("Cb4"), Some(39));
+        assert_eq!(parse_key("B#3"), Some(40));
+        assert_eq!(parse_key("A0"), Some(LOWEST_KEY));
+        assert_eq!(parse_key("C8"), Some(HIGHEST_KEY));
+    }
+
+    #[test]
+    fn rejects_names_off_the_keyboard() {
+        for name in &["G#0", "Ab0", "C#8", "D8", "A-1", "H4", "A", "A4x", ""] {
+            assert_eq!(parse_key(name), None, "{}", name);
+        }
+    }
 }
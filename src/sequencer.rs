@@ -0,0 +1,292 @@
+use std::collections::HashMap;
+
+/// A note played on the tube with id `tube`. Times are in beats.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NoteEvent {
+    pub tube: u32,
+    pub start: f64,
+    pub duration: f64,
+    /// Peak gain, from 0 to 1.
+    pub velocity: f64,
+}
+
+impl NoteEvent {
+    pub fn end(&self) -> f64 {
+        self.start + self.duration
+    }
+
+    /// Whether the note starts at a real beat and lasts a while; others
+    /// would never end, or could not be saved.
+    pub fn is_playable(&self) -> bool {
+        self.start.is_finite()
+            && self.start >= 0.0
+            && self.duration.is_finite()
+            && self.duration > 0.0
+    }
+}
+
+/// Note events, kept in order of their start, and the tempo to play them at.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Sequence {
+    /// Beats per minute.
+    pub tempo: f64,
+    pub events: Vec<NoteEvent>,
+}
+
+impl Default for Sequence {
+    fn default() -> Sequence {
+        Sequence {
+            tempo: 120.0,
+            events: Vec::new(),
+        }
+    }
+}
+
+impl Sequence {
+    pub fn seconds_per_beat(&self) -> f64 {
+        60.0 / self.tempo
+    }
+
+    /// Adds `event` after any others starting at the same beat.
+    pub fn add(&mut self, event: NoteEvent) {
+        let index = self
+            .events
+            .iter()
+            .position(|e| e.start > event.start)
+            .unwrap_or(self.events.len());
+        self.events.insert(index, event);
+    }
+
+    /// Puts the events back in order of their start, as `add` keeps them,
+    /// dropping any that cannot be played; sequences read from files may be
+    /// in any order.
+    pub fn normalize(&mut self) {
+        self.events.retain(NoteEvent::is_playable);
+        // Stable, so events starting together keep their order.
+        self.events
+            .sort_by(|a, b| a.start.partial_cmp(&b.start).unwrap());
+    }
+
+    /// The beat at which the last note ends.
+    pub fn length(&self) -> f64 {
+        self.events.iter().map(|e| e.end()).fold(0.0, f64::max)
+    }
+}
+
+/// Steps through a sequence as the audio clock advances, handing out notes
+/// shortly before they are due so they can be scheduled precisely.
+pub struct Playback {
+    /// Audio time, in seconds, of beat 0.
+    origin: f64,
+    seconds_per_beat: f64,
+    next: usize,
+}
+
+impl Playback {
+    pub fn new(sequence: &Sequence, origin: f64) -> Playback {
+        Playback {
+            origin,
+            seconds_per_beat: sequence.seconds_per_beat(),
+            next: 0,
+        }
+    }
+
+    /// Plays on at the tempo of `sequence` from audio time `now`, keeping the
+    /// beat reached so far.
+    pub fn set_tempo(&mut self, sequence: &Sequence, now: f64) {
+        let beat = (now - self.origin) / self.seconds_per_beat;
+        self.seconds_per_beat = sequence.seconds_per_beat();
+        self.origin = now - beat * self.seconds_per_beat;
+    }
+
+    /// Audio time of `beat`.
+    pub fn time(&self, beat: f64) -> f64 {
+        self.origin + beat * self.seconds_per_beat
+    }
+
+    /// The events that start before audio time `until` and have not been
+    /// handed out yet.
+    pub fn due(&mut self, sequence: &Sequence, until: f64) -> Vec<NoteEvent> {
+        let mut due = Vec::new();
+        while let Some(&event) = sequence.events.get(self.next) {
+            if self.time(event.start) >= until {
+                break;
+            }
+            due.push(event);
+            self.next += 1;
+        }
+        due
+    }
+
+    pub fn is_finished(&self, sequence: &Sequence) -> bool {
+        self.next >= sequence.events.len()
+    }
+}
+
+/// Turns tubes started and stopped by hand into note events.
+pub struct Recorder {
+    /// Audio time, in seconds, of beat 0.
+    origin: f64,
+    seconds_per_beat: f64,
+    /// Start beat of each tube sounding now.
+    held: HashMap<u32, f64>,
+    events: Vec<NoteEvent>,
+}
+
+impl Recorder {
+    pub fn new(sequence: &Sequence, origin: f64) -> Recorder {
+        Recorder {
+            origin,
+            seconds_per_beat: sequence.seconds_per_beat(),
+            held: HashMap::new(),
+            events: Vec::new(),
+        }
+    }
+
+    fn beat(&self, time: f64) -> f64 {
+        ((time - self.origin) / self.seconds_per_beat).max(0.0)
+    }
+
+    pub fn note_on(&mut self, tube: u32, time: f64) {
+        let beat = self.beat(time);
+        self.held.entry(tube).or_insert(beat);
+    }
+
+    pub fn note_off(&mut self, tube: u32, time: f64) {
+        if let Some(start) = self.held.remove(&tube) {
+            self.events.push(NoteEvent {
+                tube,
+                start,
+                duration: self.beat(time) - start,
+                velocity: 1.0,
+            });
+        }
+    }
+
+    /// Ends any notes still held at `time` and returns everything played.
+    pub fn finish(mut self, time: f64) -> Vec<NoteEvent> {
+        let held = self.held.keys().cloned().collect::<Vec<_>>();
+        for tube in held {
+            self.note_off(tube, time);
+        }
+        self.events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note(tube: u32, start: f64) -> NoteEvent {
+        NoteEvent {
+            tube,
+            start,
+            duration: 1.0,
+            velocity: 1.0,
+        }
+    }
+
+    fn starts(events: &[NoteEvent]) -> Vec<f64> {
+        events.iter().map(|e| e.start).collect()
+    }
+
+    #[test]
+    fn add_keeps_events_in_order() {
+        let mut sequence = Sequence::default();
+        for &(tube, start) in &[(1, 2.0), (2, 0.0), (3, 2.0), (4, 1.0)] {
+            sequence.add(note(tube, start));
+        }
+        assert_eq!(starts(&sequence.events), vec![0.0, 1.0, 2.0, 2.0]);
+        assert_eq!(sequence.events[2].tube, 1);
+        assert_eq!(sequence.events[3].tube, 3);
+    }
+
+    #[test]
+    fn normalize_sorts_and_drops_unplayable_events() {
+        let mut sequence = Sequence {
+            tempo: 120.0,
+            events: vec![
+                note(1, 3.0),
+                note(2, 1.0),
+                note(3, ::std::f64::NAN),
+                note(4, -1.0),
+                note(5, 1.0),
+            ],
+        };
+        sequence.normalize();
+        assert_eq!(starts(&sequence.events), vec![1.0, 1.0, 3.0]);
+        assert_eq!(sequence.events[0].tube, 2);
+        assert_eq!(sequence.events[1].tube, 5);
+    }
+
+    #[test]
+    fn notes_need_a_finite_start_and_length() {
+        assert!(note(1, 0.0).is_playable());
+        let nan = ::std::f64::NAN;
+        let inf = ::std::f64::INFINITY;
+        for &(start, duration) in &[
+            (nan, 1.0),
+            (inf, 1.0),
+            (-1.0, 1.0),
+            (0.0, nan),
+            (0.0, inf),
+            (0.0, 0.0),
+        ] {
+            let event = NoteEvent {
+                tube: 1,
+                start,
+                duration,
+                velocity: 1.0,
+            };
+            assert!(!event.is_playable(), "{:?}", event);
+        }
+    }
+
+    #[test]
+    fn due_hands_out_each_event_once() {
+        let mut sequence = Sequence::default();
+        for start in 0..4 {
+            sequence.add(note(start, start as f64));
+        }
+        // Half a second per beat, from audio time 10.
+        let mut playback = Playback::new(&sequence, 10.0);
+        assert_eq!(starts(&playback.due(&sequence, 10.6)), vec![0.0, 1.0]);
+        assert!(playback.due(&sequence, 10.6).is_empty());
+        assert_eq!(starts(&playback.due(&sequence, 12.0)), vec![2.0, 3.0]);
+        assert!(playback.is_finished(&sequence));
+    }
+
+    #[test]
+    fn tempo_change_keeps_the_current_beat() {
+        let mut sequence = Sequence::default();
+        sequence.add(note(1, 4.0));
+        let mut playback = Playback::new(&sequence, 10.0);
+        // Two beats in at 120 bpm, then twice as fast.
+        sequence.tempo = 240.0;
+        playback.set_tempo(&sequence, 11.0);
+        assert_eq!(playback.time(2.0), 11.0);
+        assert_eq!(playback.time(4.0), 11.5);
+        assert!(playback.due(&sequence, 11.5).is_empty());
+        assert_eq!(playback.due(&sequence, 11.6).len(), 1);
+    }
+
+    #[test]
+    fn recorder_turns_held_tubes_into_notes() {
+        let sequence = Sequence::default();
+        let mut recorder = Recorder::new(&sequence, 10.0);
+        recorder.note_on(1, 10.5);
+        recorder.note_on(1, 10.7);
+        recorder.note_off(1, 11.5);
+        recorder.note_on(2, 12.0);
+        let events = recorder.finish(13.0);
+        assert_eq!(events.len(), 2);
+        assert_eq!(
+            (events[0].tube, events[0].start, events[0].duration),
+            (1, 1.0, 2.0)
+        );
+        assert_eq!(
+            (events[1].tube, events[1].start, events[1].duration),
+            (2, 4.0, 2.0)
+        );
+    }
+}
@@ -0,0 +1,222 @@
+use std::f64::consts::PI;
+
+use piano::Note;
+
+/// A partial found in a spectrum.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Peak {
+    /// Hz, interpolated between bins.
+    pub frequency: f64,
+    /// Amplitude, where a full-scale sine has 1.
+    pub magnitude: f64,
+}
+
+impl Peak {
+    pub fn note(&self) -> Note {
+        Note::nearest(self.frequency)
+    }
+}
+
+/// Transforms the complex signal `re` + i`im` into its discrete Fourier
+/// transform in place, with the iterative radix-2 Cooley-Tukey algorithm.
+/// The length must be a power of two.
+pub fn fft(re: &mut [f64], im: &mut [f64]) {
+    let n = re.len();
+    assert!(n.is_power_of_two() && im.len() == n);
+
+    // Put the input in bit-reversed order, so each pass can combine
+    // neighbouring transforms.
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    let mut size = 2;
+    while size <= n {
+        let angle = -2.0 * PI / size as f64;
+        for start in (0..n / size).map(|i| i * size) {
+            for k in 0..size / 2 {
+                let (w_re, w_im) = ((angle * k as f64).cos(), (angle * k as f64).sin());
+                let (a, b) = (start + k, start + k + size / 2);
+                let t_re = re[b] * w_re - im[b] * w_im;
+                let t_im = re[b] * w_im + im[b] * w_re;
+                re[b] = re[a] - t_re;
+                im[b] = im[a] - t_im;
+                re[a] += t_re;
+                im[a] += t_im;
+            }
+        }
+        size *= 2;
+    }
+}
+
+/// `samples` multiplied by a Hann window, which tapers them to zero at
+/// both ends so partials between bins leak little into distant ones.
+pub fn hann(samples: &[f32]) -> Vec<f64> {
+    let n = samples.len() as f64;
+    samples
+        .iter()
+        .enumerate()
+        .map(|(i, &s)| s as f64 * 0.5 * (1.0 - (2.0 * PI * i as f64 / n).cos()))
+        .collect()
+}
+
+/// The amplitude of each frequency bin in `samples`, whose length must be a
+/// power of two. Bin k is centred on k * sample rate / `samples.len()` Hz;
+/// only bins below Nyquist are returned.
+pub fn spectrum(samples: &[f32]) -> Vec<f64> {
+    let mut re = hann(samples);
+    let mut im = vec![0.0; re.len()];
+    fft(&mut re, &mut im);
+    // The window halves the amplitude and the negative frequencies take
+    // the other half.
+    let scale = 4.0 / samples.len() as f64;
+    re.iter()
+        .zip(&im)
+        .take(samples.len() / 2)
+        .map(|(&re, &im)| (re * re + im * im).sqrt() * scale)
+        .collect()
+}
+
+/// The `count` strongest local maxima of `spectrum` louder than `floor`,
+/// strongest first. Bins that are not finite, as from a signal with NaNs in
+/// it, give no peaks.
+pub fn peaks(spectrum: &[f64], sample_rate: f64, count: usize, floor: f64) -> Vec<Peak> {
+    let bin_width = sample_rate / (2 * spectrum.len()) as f64;
+    let mut peaks = Vec::new();
+    for k in 1..spectrum.len().saturating_sub(1) {
+        let (a, b, c) = (spectrum[k - 1], spectrum[k], spectrum[k + 1]);
+        if !b.is_finite() || b <= floor || b < a || b <= c {
+            continue;
+        }
+        // Fit a parabola through the log amplitudes to place the peak
+        // between bins.
+        let (a, b, c) = (a.max(1e-12).ln(), b.ln(), c.max(1e-12).ln());
+        let offset = 0.5 * (a - c) / (a - 2.0 * b + c);
+        let peak = Peak {
+            frequency: (k as f64 + offset) * bin_width,
+            magnitude: (b - 0.25 * (a - c) * offset).exp(),
+        };
+        if peak.frequency.is_finite() && peak.magnitude.is_finite() {
+            peaks.push(peak);
+        }
+    }
+    peaks.sort_by(|x, y| y.magnitude.partial_cmp(&x.magnitude).unwrap());
+    peaks.truncate(count);
+    peaks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f64 = 8000.0;
+    const SIZE: usize = 1024;
+    /// Hz between bins.
+    const BIN: f64 = SAMPLE_RATE / SIZE as f64;
+
+    /// `SIZE` samples of sines, given as (frequency, amplitude).
+    fn tones(tones: &[(f64, f64)]) -> Vec<f32> {
+        (0..SIZE)
+            .map(|i| {
+                let t = i as f64 / SAMPLE_RATE;
+                tones
+                    .iter()
+                    .map(|&(f, a)| a * (2.0 * PI * f * t).sin())
+                    .sum::<f64>() as f32
+            })
+            .collect()
+    }
+
+    fn loudest_bin(spectrum: &[f64]) -> usize {
+        (0..spectrum.len()).fold(0, |best, k| {
+            if spectrum[k] > spectrum[best] {
+                k
+            } else {
+                best
+            }
+        })
+    }
+
+    #[test]
+    fn fft_matches_the_dft() {
+        let n = 16;
+        let signal = (0..n)
+            .map(|i| ((i * 7 % 5) as f64 - 2.0, (i % 3) as f64))
+            .collect::<Vec<_>>();
+        let mut re = signal.iter().map(|s| s.0).collect::<Vec<_>>();
+        let mut im = signal.iter().map(|s| s.1).collect::<Vec<_>>();
+        fft(&mut re, &mut im);
+        for k in 0..n {
+            let (mut dft_re, mut dft_im) = (0.0, 0.0);
+            for (j, &(x_re, x_im)) in signal.iter().enumerate() {
+                let angle = -2.0 * PI * (j * k) as f64 / n as f64;
+                dft_re += x_re * angle.cos() - x_im * angle.sin();
+                dft_im += x_re * angle.sin() + x_im * angle.cos();
+            }
+            assert!((re[k] - dft_re).abs() < 1e-9 && (im[k] - dft_im).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn sine_on_a_bin() {
+        let spectrum = spectrum(&tones(&[(128.0 * BIN, 0.5)]));
+        assert_eq!(spectrum.len(), SIZE / 2);
+        assert_eq!(loudest_bin(&spectrum), 128);
+        assert!((spectrum[128] - 0.5).abs() < 1e-3);
+        let peaks = peaks(&spectrum, SAMPLE_RATE, 4, 0.01);
+        assert_eq!(peaks.len(), 1);
+        assert!((peaks[0].frequency - 1000.0).abs() < 1e-6);
+        assert!((peaks[0].magnitude - 0.5).abs() < 0.01);
+    }
+
+    #[test]
+    fn sine_between_bins() {
+        // A third of the way from bin 56 to 57.
+        let frequency = (56.0 + 1.0 / 3.0) * BIN;
+        let spectrum = spectrum(&tones(&[(frequency, 1.0)]));
+        assert_eq!(loudest_bin(&spectrum), 56);
+        let peaks = peaks(&spectrum, SAMPLE_RATE, 4, 0.01);
+        assert_eq!(peaks.len(), 1);
+        assert!((peaks[0].frequency - frequency).abs() < 0.1 * BIN);
+        assert!((peaks[0].magnitude - 1.0).abs() < 0.05);
+    }
+
+    #[test]
+    fn two_tones_strongest_first() {
+        let spectrum = spectrum(&tones(&[(440.0, 0.3), (1250.0, 0.8)]));
+        let peaks = peaks(&spectrum, SAMPLE_RATE, 2, 0.01);
+        assert_eq!(peaks.len(), 2);
+        assert!((peaks[0].frequency - 1250.0).abs() < 0.1 * BIN);
+        assert!((peaks[1].frequency - 440.0).abs() < 0.1 * BIN);
+        assert!((peaks[0].magnitude - 0.8).abs() < 0.05);
+        assert!((peaks[1].magnitude - 0.3).abs() < 0.05);
+        assert_eq!(peaks[1].note().key, 49);
+    }
+
+    #[test]
+    fn non_finite_bins_give_no_peaks() {
+        let mut bins = vec![0.0; 16];
+        bins[3] = ::std::f64::NAN;
+        bins[6] = ::std::f64::INFINITY;
+        bins[9] = 0.5;
+        bins[12] = ::std::f64::NAN;
+        bins[13] = 0.25;
+        let found = peaks(&bins, SAMPLE_RATE, 8, 0.01);
+        assert_eq!(found.len(), 2);
+        assert!((found[0].frequency - 9.0 * SAMPLE_RATE / 32.0).abs() < 1e-6);
+
+        let mut samples = tones(&[(1000.0, 0.5)]);
+        samples[100] = ::std::f32::NAN;
+        assert!(peaks(&spectrum(&samples), SAMPLE_RATE, 4, 0.01).is_empty());
+    }
+}
@@ -0,0 +1,23 @@
+/// The subset of the canvas 2D API the instrument draws with, so the same
+/// drawing code can target the browser canvas or an SVG document.
+pub trait Surface {
+    fn begin_path(&mut self);
+    fn move_to(&mut self, x: f64, y: f64);
+    fn line_to(&mut self, x: f64, y: f64);
+    fn arc(
+        &mut self,
+        x: f64,
+        y: f64,
+        radius: f64,
+        start_angle: f64,
+        end_angle: f64,
+        anticlockwise: bool,
+    );
+    fn set_line_width(&mut self, width: f64);
+    fn set_stroke_style(&mut self, style: &str);
+    fn set_fill_style(&mut self, style: &str);
+    fn stroke(&mut self);
+    fn fill(&mut self);
+    fn fill_rect(&mut self, x: f64, y: f64, width: f64, height: f64);
+    fn fill_text(&mut self, text: &str, x: f64, y: f64);
+}
@@ -0,0 +1,176 @@
+use std::f64::consts::PI;
+use std::fmt::Write;
+
+use surface::Surface;
+
+/// A `Surface` that records drawing commands as an SVG document.
+pub struct SvgSurface {
+    width: f64,
+    height: f64,
+    body: String,
+    path: String,
+    line_width: f64,
+    stroke_style: String,
+    fill_style: String,
+}
+
+impl SvgSurface {
+    pub fn new(width: f64, height: f64) -> SvgSurface {
+        SvgSurface {
+            width,
+            height,
+            body: String::new(),
+            path: String::new(),
+            line_width: 1.0,
+            stroke_style: "#000".to_string(),
+            fill_style: "#000".to_string(),
+        }
+    }
+
+    pub fn finish(self) -> String {
+        format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w}\" height=\"{h}\" \
+             viewBox=\"0 0 {w} {h}\">\n{body}</svg>\n",
+            w = self.width,
+            h = self.height,
+            body = self.body,
+        )
+    }
+}
+
+/// Rounds coordinates to hundredths of a pixel to keep output compact and
+/// stable across float noise.
+fn round(v: f64) -> f64 {
+    (v * 100.0).round() / 100.0
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+impl Surface for SvgSurface {
+    fn begin_path(&mut self) {
+        self.path.clear();
+    }
+
+    fn move_to(&mut self, x: f64, y: f64) {
+        write!(self.path, "M{} {} ", round(x), round(y)).unwrap();
+    }
+
+    fn line_to(&mut self, x: f64, y: f64) {
+        if self.path.is_empty() {
+            self.move_to(x, y);
+        } else {
+            write!(self.path, "L{} {} ", round(x), round(y)).unwrap();
+        }
+    }
+
+    fn arc(
+        &mut self,
+        x: f64,
+        y: f64,
+        radius: f64,
+        start_angle: f64,
+        end_angle: f64,
+        anticlockwise: bool,
+    ) {
+        // Canvas sweeps clockwise (in screen space) unless `anticlockwise`;
+        // a sweep of a full turn or more draws the whole circle.
+        let mut sweep = if anticlockwise {
+            start_angle - end_angle
+        } else {
+            end_angle - start_angle
+        };
+        if sweep >= 2.0 * PI {
+            sweep = 2.0 * PI;
+        } else {
+            sweep %= 2.0 * PI;
+            if sweep < 0.0 {
+                sweep += 2.0 * PI;
+            }
+        }
+        let dir = if anticlockwise { -1.0 } else { 1.0 };
+
+        self.line_to(
+            x + radius * start_angle.cos(),
+            y + radius * start_angle.sin(),
+        );
+        // SVG arcs between coincident points vanish, so draw in halves at most.
+        let pieces = (sweep / PI).ceil().max(1.0) as usize;
+        for i in 1..=pieces {
+            let angle = start_angle + dir * sweep * i as f64 / pieces as f64;
+            write!(
+                self.path,
+                "A{r} {r} 0 0 {s} {} {} ",
+                round(x + radius * angle.cos()),
+                round(y + radius * angle.sin()),
+                r = round(radius),
+                s = if anticlockwise { 0 } else { 1 },
+            )
+            .unwrap();
+        }
+    }
+
+    fn set_line_width(&mut self, width: f64) {
+        self.line_width = width;
+    }
+
+    fn set_stroke_style(&mut self, style: &str) {
+        self.stroke_style = escape(style);
+    }
+
+    fn set_fill_style(&mut self, style: &str) {
+        self.fill_style = escape(style);
+    }
+
+    fn stroke(&mut self) {
+        if self.path.is_empty() {
+            return;
+        }
+        writeln!(
+            self.body,
+            "<path d=\"{}\" fill=\"none\" stroke=\"{}\" stroke-width=\"{}\"/>",
+            self.path.trim_right(),
+            self.stroke_style,
+            self.line_width
+        )
+        .unwrap();
+    }
+
+    fn fill(&mut self) {
+        if self.path.is_empty() {
+            return;
+        }
+        writeln!(
+            self.body,
+            "<path d=\"{}\" fill=\"{}\"/>",
+            self.path.trim_right(),
+            self.fill_style
+        )
+        .unwrap();
+    }
+
+    fn fill_rect(&mut self, x: f64, y: f64, width: f64, height: f64) {
+        writeln!(
+            self.body,
+            "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"{}\"/>",
+            x, y, width, height, self.fill_style
+        )
+        .unwrap();
+    }
+
+    fn fill_text(&mut self, text: &str, x: f64, y: f64) {
+        writeln!(
+            self.body,
+            "<text x=\"{}\" y=\"{}\" fill=\"{}\">{}</text>",
+            x,
+            y,
+            self.fill_style,
+            escape(text)
+        )
+        .unwrap();
+    }
+}
@@ -0,0 +1,455 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::f64::consts::PI;
+
+/// A sound backend driving one oscillator ("voice") per `AudioSource`,
+/// keyed by the source's id.
+pub trait Synth {
+    fn add_voice(&mut self, id: usize, frequency: f64);
+    /// Begins the attack of the voice's envelope, peaking at `velocity`.
+    fn start(&mut self, id: usize, velocity: f64);
+    /// Begins the release of the voice's envelope.
+    fn pause(&mut self, id: usize);
+    /// Releases the voice and then discards it.
+    fn stop(&mut self, id: usize);
+    fn set_frequency(&mut self, id: usize, frequency: f64);
+    /// Sets the voice's waveform as sine amplitudes of each harmonic;
+    /// `amplitudes[0]` is the (ignored) DC term.
+    fn set_harmonics(&mut self, id: usize, amplitudes: &[f64]);
+    fn set_envelope(&mut self, id: usize, envelope: Envelope);
+    /// Seconds elapsed on the backend's clock.
+    fn current_time(&self) -> f64;
+    /// Plays a note on the voice from time `at` for `duration` seconds,
+    /// peaking at `velocity`. The voice should be silent by then.
+    fn schedule_note(&mut self, id: usize, at: f64, duration: f64, velocity: f64);
+}
+
+/// An attack/decay/sustain/release envelope. Times are in seconds and the
+/// sustain level is a fraction of the peak.
+///
+/// Each stage is a linear ramp that starts from wherever the gain is, so a
+/// note restarted during its release rises from that level without a jump.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Envelope {
+    pub attack: f64,
+    pub decay: f64,
+    pub sustain: f64,
+    pub release: f64,
+}
+
+impl Default for Envelope {
+    fn default() -> Envelope {
+        Envelope {
+            attack: 0.02,
+            decay: 0.1,
+            sustain: 0.8,
+            release: 0.15,
+        }
+    }
+}
+
+impl Envelope {
+    /// Clamps times to be non-negative and the sustain level into [0, 1].
+    pub fn clamped(self) -> Envelope {
+        Envelope {
+            attack: self.attack.max(0.0),
+            decay: self.decay.max(0.0),
+            sustain: self.sustain.max(0.0).min(1.0),
+            release: self.release.max(0.0),
+        }
+    }
+
+    /// The gain ramps, as (target, seconds), that follow a note on peaking
+    /// at `velocity`.
+    pub fn on(&self, velocity: f64) -> [(f64, f64); 2] {
+        [
+            (velocity, self.attack),
+            (self.sustain * velocity, self.decay),
+        ]
+    }
+
+    /// The gain ramp, as (target, seconds), that follows a note off.
+    pub fn off(&self) -> (f64, f64) {
+        (0.0, self.release)
+    }
+
+    /// The gain ramps, as (target, seconds), of a note that starts from
+    /// silence, peaks at `velocity` and is released after `duration`
+    /// seconds, possibly before reaching its sustain level.
+    pub fn note(&self, velocity: f64, duration: f64) -> Vec<(f64, f64)> {
+        let mut ramps = Vec::new();
+        let mut level = 0.0;
+        let mut elapsed = 0.0;
+        for &(target, seconds) in self.on(velocity).iter() {
+            if elapsed + seconds >= duration {
+                let part = duration - elapsed;
+                level += (target - level) * if seconds > 0.0 { part / seconds } else { 1.0 };
+                ramps.push((level, part));
+                elapsed = duration;
+                break;
+            }
+            ramps.push((target, seconds));
+            level = target;
+            elapsed += seconds;
+        }
+        if elapsed < duration {
+            ramps.push((level, duration - elapsed));
+        }
+        ramps.push(self.off());
+        ramps
+    }
+}
+
+/// Amplitudes of the first `count` harmonics of a tube, falling off as
+/// 1/n^`rolloff`. A tube closed at one end only supports odd harmonics.
+/// The result is scaled so the waveform can never exceed unit amplitude.
+pub fn harmonics(open: bool, rolloff: f64, count: usize) -> Vec<f64> {
+    let mut amplitudes = vec![0.0; count + 1];
+    for n in 1..=count {
+        if open || n % 2 == 1 {
+            amplitudes[n] = 1.0 / (n as f64).powf(rolloff);
+        }
+    }
+    let total: f64 = amplitudes.iter().sum();
+    for a in &mut amplitudes {
+        *a /= total;
+    }
+    amplitudes
+}
+
+struct Voice {
+    frequency: f64,
+    amplitudes: Vec<f64>,
+    phase: f64,
+    envelope: Envelope,
+    gain: f64,
+    /// Pending linear ramps as (target gain, frames to reach it).
+    ramps: VecDeque<(f64, usize)>,
+    /// Whether the voice is discarded once its release ends.
+    stopping: bool,
+}
+
+impl Voice {
+    fn schedule(&mut self, ramps: &[(f64, f64)], sample_rate: f64) {
+        self.ramps.clear();
+        for &(target, seconds) in ramps {
+            let frames = (seconds * sample_rate).round() as usize;
+            self.ramps.push_back((target, frames));
+        }
+    }
+
+    /// Advances the envelope by one frame, returning the gain to apply.
+    fn next_gain(&mut self) -> f64 {
+        loop {
+            let (target, frames) = match self.ramps.front() {
+                Some(&ramp) => ramp,
+                None => break,
+            };
+            if frames == 0 {
+                self.gain = target;
+                self.ramps.pop_front();
+                continue;
+            }
+            self.gain += (target - self.gain) / frames as f64;
+            self.ramps[0].1 -= 1;
+            break;
+        }
+        self.gain
+    }
+
+    fn is_silent(&self) -> bool {
+        self.gain == 0.0 && self.ramps.is_empty()
+    }
+}
+
+/// Renders voices to mono f32 PCM in plain Rust, mirroring what the Web
+/// Audio graph does: every voice is a sum of harmonics, shaped by its
+/// envelope, into the master bus.
+pub struct OfflineSynth {
+    sample_rate: f64,
+    /// Frames rendered so far.
+    frames: u64,
+    voices: BTreeMap<usize, Voice>,
+}
+
+impl OfflineSynth {
+    pub fn new(sample_rate: f64) -> OfflineSynth {
+        OfflineSynth {
+            sample_rate,
+            frames: 0,
+            voices: BTreeMap::new(),
+        }
+    }
+
+    pub fn sample_rate(&self) -> f64 {
+        self.sample_rate
+    }
+
+    /// Renders the next `frames` samples. Oscillator phase carries over
+    /// between calls, so consecutive buffers join without discontinuities.
+    pub fn render(&mut self, frames: usize) -> Vec<f32> {
+        let mut out = vec![0.0; frames];
+        self.frames += frames as u64;
+        for voice in self.voices.values_mut() {
+            if voice.is_silent() {
+                continue;
+            }
+            let step = 2.0 * PI * voice.frequency / self.sample_rate;
+            // Like Web Audio, drop partials at or above Nyquist.
+            let nyquist = self.sample_rate / 2.0;
+            let partials = voice
+                .amplitudes
+                .iter()
+                .enumerate()
+                .skip(1)
+                .filter(|&(n, &a)| a != 0.0 && n as f64 * voice.frequency < nyquist)
+                .map(|(n, &a)| (n as f64, a))
+                .collect::<Vec<_>>();
+            for sample in out.iter_mut() {
+                let value: f64 = partials
+                    .iter()
+                    .map(|&(n, a)| a * (n * voice.phase).sin())
+                    .sum();
+                *sample += (voice.next_gain() * value) as f32;
+                voice.phase = (voice.phase + step) % (2.0 * PI);
+            }
+        }
+        let finished = self
+            .voices
+            .iter()
+            .filter(|&(_, voice)| voice.stopping && voice.is_silent())
+            .map(|(&id, _)| id)
+            .collect::<Vec<_>>();
+        for id in finished {
+            self.voices.remove(&id);
+        }
+        out
+    }
+}
+
+impl Synth for OfflineSynth {
+    fn add_voice(&mut self, id: usize, frequency: f64) {
+        self.voices.insert(
+            id,
+            Voice {
+                frequency,
+                amplitudes: vec![0.0, 1.0],
+                phase: 0.0,
+                envelope: Envelope::default(),
+                gain: 0.0,
+                ramps: VecDeque::new(),
+                stopping: false,
+            },
+        );
+    }
+
+    fn start(&mut self, id: usize, velocity: f64) {
+        let sample_rate = self.sample_rate;
+        if let Some(voice) = self.voices.get_mut(&id) {
+            let ramps = voice.envelope.on(velocity);
+            voice.schedule(&ramps, sample_rate);
+        }
+    }
+
+    fn pause(&mut self, id: usize) {
+        let sample_rate = self.sample_rate;
+        if let Some(voice) = self.voices.get_mut(&id) {
+            let ramp = voice.envelope.off();
+            voice.schedule(&[ramp], sample_rate);
+        }
+    }
+
+    fn stop(&mut self, id: usize) {
+        self.pause(id);
+        if let Some(voice) = self.voices.get_mut(&id) {
+            voice.stopping = true;
+        }
+    }
+
+    fn set_frequency(&mut self, id: usize, frequency: f64) {
+        if let Some(voice) = self.voices.get_mut(&id) {
+            voice.frequency = frequency;
+        }
+    }
+
+    fn set_harmonics(&mut self, id: usize, amplitudes: &[f64]) {
+        if let Some(voice) = self.voices.get_mut(&id) {
+            voice.amplitudes = amplitudes.to_vec();
+        }
+    }
+
+    fn set_envelope(&mut self, id: usize, envelope: Envelope) {
+        if let Some(voice) = self.voices.get_mut(&id) {
+            voice.envelope = envelope;
+        }
+    }
+
+    fn current_time(&self) -> f64 {
+        self.frames as f64 / self.sample_rate
+    }
+
+    fn schedule_note(&mut self, id: usize, at: f64, duration: f64, velocity: f64) {
+        let (now, sample_rate) = (self.current_time(), self.sample_rate);
+        if let Some(voice) = self.voices.get_mut(&id) {
+            let mut ramps = vec![(0.0, (at - now).max(0.0))];
+            ramps.extend(voice.envelope.note(velocity, duration));
+            voice.schedule(&ramps, sample_rate);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Frames per second in envelope tests, so a frame is a millisecond.
+    const RATE: f64 = 1000.0;
+
+    fn voice(envelope: Envelope) -> Voice {
+        Voice {
+            frequency: 440.0,
+            amplitudes: vec![0.0, 1.0],
+            phase: 0.0,
+            envelope,
+            gain: 0.0,
+            ramps: VecDeque::new(),
+            stopping: false,
+        }
+    }
+
+    /// The gain after each of the next `frames` frames.
+    fn gains(voice: &mut Voice, frames: usize) -> Vec<f64> {
+        (0..frames).map(|_| voice.next_gain()).collect()
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn attack_decay_and_sustain() {
+        let envelope = Envelope {
+            attack: 0.01,
+            decay: 0.02,
+            sustain: 0.5,
+            release: 0.1,
+        };
+        let mut voice = voice(envelope);
+        voice.schedule(&envelope.on(0.8), RATE);
+        let gains = gains(&mut voice, 100);
+        assert!(close(gains[4], 0.4));
+        assert!(close(gains[9], 0.8));
+        // Halfway from the peak to the sustain level.
+        assert!(close(gains[19], 0.6));
+        assert!(close(gains[29], 0.4));
+        assert!(gains[29..].iter().all(|&g| close(g, 0.4)));
+    }
+
+    #[test]
+    fn release_from_mid_attack() {
+        let envelope = Envelope {
+            attack: 0.02,
+            decay: 0.0,
+            sustain: 1.0,
+            release: 0.04,
+        };
+        let mut voice = voice(envelope);
+        voice.schedule(&envelope.on(1.0), RATE);
+        assert!(close(gains(&mut voice, 10)[9], 0.5));
+        voice.schedule(&[envelope.off()], RATE);
+        let gains = gains(&mut voice, 50);
+        // Down from where the attack got to, not from the peak.
+        assert!(close(gains[19], 0.25));
+        assert!(close(gains[39], 0.0));
+        assert!(voice.is_silent());
+    }
+
+    #[test]
+    fn restrike_during_release() {
+        let envelope = Envelope {
+            attack: 0.02,
+            decay: 0.0,
+            sustain: 1.0,
+            release: 0.04,
+        };
+        let mut voice = voice(envelope);
+        voice.schedule(&envelope.on(1.0), RATE);
+        gains(&mut voice, 30);
+        voice.schedule(&[envelope.off()], RATE);
+        assert!(close(gains(&mut voice, 20)[19], 0.5));
+        voice.schedule(&envelope.on(1.0), RATE);
+        let gains = gains(&mut voice, 20);
+        // Up from where the release got to, not from silence.
+        assert!(close(gains[9], 0.75));
+        assert!(close(gains[19], 1.0));
+    }
+
+    #[test]
+    fn zero_length_stages() {
+        let envelope = Envelope {
+            attack: 0.0,
+            decay: 0.0,
+            sustain: 0.5,
+            release: 0.0,
+        };
+        let mut voice = voice(envelope);
+        voice.schedule(&envelope.on(1.0), RATE);
+        assert_eq!(voice.next_gain(), 0.5);
+        voice.schedule(&[envelope.off()], RATE);
+        assert_eq!(voice.next_gain(), 0.0);
+        assert!(voice.is_silent());
+    }
+
+    #[test]
+    fn note_shorter_than_attack() {
+        let envelope = Envelope {
+            attack: 0.1,
+            decay: 0.1,
+            sustain: 0.5,
+            release: 0.2,
+        };
+        // Released at a quarter of the way up to the peak.
+        let ramps = envelope.note(0.8, 0.025);
+        assert_eq!(ramps.len(), 2);
+        assert!(close(ramps[0].0, 0.2) && close(ramps[0].1, 0.025));
+        assert_eq!(ramps[1], (0.0, 0.2));
+        // A long note holds its sustain level until released.
+        let ramps = envelope.note(1.0, 1.0);
+        assert_eq!(ramps, vec![(1.0, 0.1), (0.5, 0.1), (0.5, 0.8), (0.0, 0.2)]);
+    }
+
+    #[test]
+    fn clamps_envelopes() {
+        let envelope = Envelope {
+            attack: -1.0,
+            decay: 0.1,
+            sustain: 1.5,
+            release: -0.5,
+        }
+        .clamped();
+        assert_eq!(envelope.attack, 0.0);
+        assert_eq!(envelope.sustain, 1.0);
+        assert_eq!(envelope.release, 0.0);
+    }
+
+    #[test]
+    fn harmonics_sum_to_one() {
+        for &open in [false, true].iter() {
+            for &rolloff in [0.0, 1.0, 2.5].iter() {
+                let amplitudes = harmonics(open, rolloff, 16);
+                assert_eq!(amplitudes.len(), 17);
+                assert_eq!(amplitudes[0], 0.0);
+                let total: f64 = amplitudes.iter().sum();
+                assert!((total - 1.0).abs() < 1e-12);
+            }
+        }
+    }
+
+    #[test]
+    fn closed_tubes_have_only_odd_harmonics() {
+        let amplitudes = harmonics(false, 1.0, 8);
+        for n in (2..=8).filter(|n| n % 2 == 0) {
+            assert_eq!(amplitudes[n], 0.0);
+        }
+        assert!(amplitudes[3] > 0.0);
+    }
+}
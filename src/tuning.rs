@@ -0,0 +1,523 @@
+use piano::{self, Note};
+
+/// Maps piano keys to frequencies.
+///
+/// The scale is a list of ratios above its root, in the style of a Scala
+/// `.scl` file: unison is implied and the last ratio is the period (usually
+/// 2/1). `mapping` assigns keys to scale degrees, starting at `root_key` and
+/// repeating every `mapping.len()` keys, shifted each time by the ratio of
+/// `period_degree`; unmapped keys have no pitch. Without a mapping, keys
+/// walk the scale linearly.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Tuning {
+    pub name: String,
+    pub ratios: Vec<f64>,
+    pub mapping: Option<Vec<Option<usize>>>,
+    pub period_degree: usize,
+    pub root_key: usize,
+    pub reference_key: usize,
+    pub reference_frequency: f64,
+}
+
+/// C4, the root of the built-in scales.
+const MIDDLE_C: usize = 40;
+
+impl Default for Tuning {
+    fn default() -> Tuning {
+        Tuning::equal(440.0)
+    }
+}
+
+/// Reduces `ratio` into the octave [1, 2).
+fn octave_reduce(mut ratio: f64) -> f64 {
+    while ratio >= 2.0 {
+        ratio /= 2.0;
+    }
+    while ratio < 1.0 {
+        ratio *= 2.0;
+    }
+    ratio
+}
+
+/// Quotient and non-negative remainder of `a / n`.
+fn floor_div(a: i32, n: i32) -> (i32, i32) {
+    let r = (a % n + n) % n;
+    ((a - r) / n, r)
+}
+
+/// A 12-note scale on C built from a chain of fifths of size `fifth`,
+/// running from Eb (-3 fifths) to G# (+8 fifths).
+fn chain_of_fifths(fifth: f64) -> Vec<f64> {
+    let mut ratios = vec![0.0; 12];
+    for k in -3i32..=8 {
+        let semitone = ((7 * k) % 12 + 12) % 12;
+        ratios[semitone as usize] = octave_reduce(fifth.powi(k));
+    }
+    ratios.remove(0);
+    ratios.push(2.0);
+    ratios
+}
+
+impl Tuning {
+    fn twelve_note(name: &str, ratios: Vec<f64>, reference_frequency: f64) -> Tuning {
+        Tuning {
+            name: name.to_string(),
+            period_degree: ratios.len(),
+            ratios,
+            mapping: None,
+            root_key: MIDDLE_C,
+            reference_key: piano::A4,
+            reference_frequency,
+        }
+    }
+
+    /// Twelve-tone equal temperament with A4 at `reference_frequency`.
+    pub fn equal(reference_frequency: f64) -> Tuning {
+        let ratios = (1..=12).map(|i| 2f64.powf(i as f64 / 12.0)).collect();
+        Tuning::twelve_note("12-TET", ratios, reference_frequency)
+    }
+
+    /// Five-limit just intonation on C.
+    pub fn just(reference_frequency: f64) -> Tuning {
+        let ratios = [
+            (16, 15),
+            (9, 8),
+            (6, 5),
+            (5, 4),
+            (4, 3),
+            (45, 32),
+            (3, 2),
+            (8, 5),
+            (5, 3),
+            (9, 5),
+            (15, 8),
+            (2, 1),
+        ]
+        .iter()
+        .map(|&(n, d)| n as f64 / d as f64)
+        .collect();
+        Tuning::twelve_note("just intonation", ratios, reference_frequency)
+    }
+
+    /// Pythagorean tuning on C, built from pure 3/2 fifths.
+    pub fn pythagorean(reference_frequency: f64) -> Tuning {
+        Tuning::twelve_note("Pythagorean", chain_of_fifths(1.5), reference_frequency)
+    }
+
+    /// Quarter-comma meantone on C, whose fifths are tempered to make pure
+    /// 5/4 major thirds.
+    pub fn meantone(reference_frequency: f64) -> Tuning {
+        let fifth = 5f64.powf(0.25);
+        Tuning::twelve_note(
+            "quarter-comma meantone",
+            chain_of_fifths(fifth),
+            reference_frequency,
+        )
+    }
+
+    /// Checks that the scale has positive ratios, that any mapping maps
+    /// keys to its degrees, and that the reference key has a real pitch.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.ratios.is_empty() {
+            return Err("the scale has no notes".to_string());
+        }
+        if let Some(&ratio) = self.ratios.iter().find(|r| !r.is_finite() || **r <= 0.0) {
+            return Err(format!("the scale has ratio {}", ratio));
+        }
+        if let Some(ref mapping) = self.mapping {
+            if mapping.is_empty() {
+                return Err("the keyboard mapping is empty".to_string());
+            }
+            if let Some(degree) = mapping
+                .iter()
+                .filter_map(|&d| d)
+                .find(|&d| d > self.ratios.len())
+            {
+                return Err(format!("degree {} is not in the scale", degree));
+            }
+        }
+        if !self.reference_frequency.is_finite() || self.reference_frequency <= 0.0 {
+            return Err(format!(
+                "reference frequency {}Hz is not a pitch",
+                self.reference_frequency
+            ));
+        }
+        if self.ratio(self.reference_key).is_none() {
+            return Err("the reference key is not mapped".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn preset(name: &str, reference_frequency: f64) -> Option<Tuning> {
+        match &*name.to_lowercase() {
+            "equal" | "12-tet" | "12tet" => Some(Tuning::equal(reference_frequency)),
+            "just" => Some(Tuning::just(reference_frequency)),
+            "pythagorean" => Some(Tuning::pythagorean(reference_frequency)),
+            "meantone" => Some(Tuning::meantone(reference_frequency)),
+            _ => None,
+        }
+    }
+
+    /// Builds a tuning from the text of a Scala `.scl` file and, optionally,
+    /// a `.kbm` keyboard mapping. Without a mapping the scale is rooted at
+    /// C4 and A4 keeps `reference_frequency`.
+    pub fn scala(scl: &str, kbm: Option<&str>, reference_frequency: f64) -> Result<Tuning, String> {
+        let (name, ratios) = parse_scl(scl)?;
+        let mut tuning = Tuning {
+            name,
+            period_degree: ratios.len(),
+            ratios,
+            mapping: None,
+            root_key: MIDDLE_C,
+            reference_key: piano::A4,
+            reference_frequency,
+        };
+        if let Some(kbm) = kbm {
+            tuning.apply_kbm(kbm)?;
+        }
+        tuning.validate()?;
+        Ok(tuning)
+    }
+
+    fn apply_kbm(&mut self, kbm: &str) -> Result<(), String> {
+        let mut lines = kbm
+            .lines()
+            .map(|l| l.trim())
+            .filter(|l| !l.starts_with('!') && !l.is_empty());
+        let mut field = |what: &str| {
+            lines
+                .next()
+                .map(|l| l.split_whitespace().next().unwrap_or(""))
+                .ok_or_else(|| format!("kbm: missing {}", what))
+        };
+        let number = |s: &str, what: &str| -> Result<usize, String> {
+            s.parse().map_err(|_| format!("kbm: bad {} {:?}", what, s))
+        };
+        // MIDI note numbers are 20 above piano keys.
+        let to_key = |midi: usize, what: &str| -> Result<usize, String> {
+            if midi < 20 + piano::LOWEST_KEY {
+                Err(format!("kbm: {} {} is below the keyboard", what, midi))
+            } else {
+                Ok(midi - 20)
+            }
+        };
+
+        let size = number(field("map size")?, "map size")?;
+        field("first note")?;
+        field("last note")?;
+        let middle = number(field("middle note")?, "middle note")?;
+        let reference = number(field("reference note")?, "reference note")?;
+        let frequency = field("reference frequency")?;
+        let frequency: f64 = frequency
+            .parse()
+            .map_err(|_| format!("kbm: bad reference frequency {:?}", frequency))?;
+        if !frequency.is_finite() || frequency <= 0.0 {
+            return Err(format!(
+                "kbm: reference frequency {} is not a pitch",
+                frequency
+            ));
+        }
+        let period_degree = number(field("octave degree")?, "octave degree")?;
+
+        // The size comes from the file, so it may be far too large to
+        // allocate for up front.
+        let mut mapping = Vec::new();
+        for _ in 0..size {
+            // Trailing entries may be omitted; they are unmapped.
+            let degree = match field("mapping") {
+                Ok("x") | Err(_) => None,
+                Ok(s) => Some(number(s, "mapping entry")?),
+            };
+            if degree.map_or(false, |d| d > self.ratios.len()) {
+                return Err(format!("kbm: degree {:?} is not in the scale", degree));
+            }
+            mapping.push(degree);
+        }
+
+        self.root_key = to_key(middle, "middle note")?;
+        self.reference_key = to_key(reference, "reference note")?;
+        self.reference_frequency = frequency;
+        self.period_degree = if period_degree == 0 {
+            self.ratios.len()
+        } else {
+            period_degree
+        };
+        self.mapping = if size == 0 { None } else { Some(mapping) };
+        Ok(())
+    }
+
+    /// Ratio of scale degree `degree` above the root, continuing past the
+    /// period.
+    fn degree_ratio(&self, degree: i32) -> f64 {
+        let n = self.ratios.len() as i32;
+        let period = self.ratios[self.ratios.len() - 1];
+        let (periods, step) = floor_div(degree, n);
+        let within = if step == 0 {
+            1.0
+        } else {
+            self.ratios[step as usize - 1]
+        };
+        period.powi(periods) * within
+    }
+
+    /// Ratio of `key` above the root, or `None` if the key is unmapped.
+    fn ratio(&self, key: usize) -> Option<f64> {
+        let offset = key as i32 - self.root_key as i32;
+        match self.mapping {
+            None => Some(self.degree_ratio(offset)),
+            Some(ref mapping) => {
+                let m = mapping.len() as i32;
+                let (repeats, index) = floor_div(offset, m);
+                let degree = mapping[index as usize]?;
+                let period = self.degree_ratio(self.period_degree as i32);
+                Some(period.powi(repeats) * self.degree_ratio(degree as i32))
+            }
+        }
+    }
+
+    pub fn frequency(&self, key: usize) -> Option<f64> {
+        let reference = self.ratio(self.reference_key)?;
+        Some(self.reference_frequency * self.ratio(key)? / reference)
+    }
+
+    /// The mapped key nearest in pitch to `f`. Without a finite distance to
+    /// any key, as for a NaN frequency, falls back to the nearest piano key.
+    pub fn nearest(&self, f: f64) -> Note {
+        (piano::LOWEST_KEY..=piano::HIGHEST_KEY)
+            .filter_map(|key| {
+                self.frequency(key).map(|k| Note {
+                    key,
+                    cents: piano::cents(f, k),
+                })
+            })
+            .filter(|note| note.cents.is_finite())
+            .min_by(|a, b| a.cents.abs().partial_cmp(&b.cents.abs()).unwrap())
+            .unwrap_or_else(|| Note::nearest(f))
+    }
+
+    /// The next mapped key above (`up`) or below `key`, if any.
+    pub fn step(&self, key: usize, up: bool) -> Option<usize> {
+        if up {
+            (key + 1..=piano::HIGHEST_KEY).find(|&k| self.frequency(k).is_some())
+        } else {
+            (piano::LOWEST_KEY..key)
+                .rev()
+                .find(|&k| self.frequency(k).is_some())
+        }
+    }
+}
+
+/// Parses a Scala `.scl` file into its description and ratios.
+pub fn parse_scl(scl: &str) -> Result<(String, Vec<f64>), String> {
+    let mut lines = scl.lines().filter(|l| !l.starts_with('!'));
+    let description = lines
+        .next()
+        .ok_or_else(|| "scl: missing description".to_string())?
+        .trim()
+        .to_string();
+    let count = lines
+        .next()
+        .ok_or_else(|| "scl: missing note count".to_string())?
+        .trim();
+    let count: usize = count
+        .parse()
+        .map_err(|_| format!("scl: bad note count {:?}", count))?;
+    if count == 0 {
+        return Err("scl: scale has no notes".to_string());
+    }
+    let mut ratios = Vec::new();
+    for line in lines.take(count) {
+        let pitch = line.split_whitespace().next().unwrap_or("");
+        let ratio = if pitch.contains('.') {
+            pitch
+                .parse::<f64>()
+                .ok()
+                .map(|cents| 2f64.powf(cents / 1200.0))
+        } else {
+            let mut parts = pitch.splitn(2, '/');
+            let numerator = parts.next().and_then(|n| n.parse::<f64>().ok());
+            let denominator = parts.next().map_or(Some(1.0), |d| d.parse::<f64>().ok());
+            match (numerator, denominator) {
+                (Some(n), Some(d)) if d != 0.0 => Some(n / d),
+                _ => None,
+            }
+        };
+        match ratio {
+            Some(r) if r.is_finite() && r > 0.0 => ratios.push(r),
+            _ => return Err(format!("scl: bad pitch {:?}", line.trim())),
+        }
+    }
+    if ratios.len() != count {
+        return Err(format!(
+            "scl: expected {} pitches, found {}",
+            count,
+            ratios.len()
+        ));
+    }
+    Ok((description, ratios))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCL_12TET: &str = concat!(
+        "12-TET\n12\n",
+        "100.\n200.\n300.\n400.\n500.\n600.\n",
+        "700.\n800.\n900.\n1000.\n1100.\n2/1\n"
+    );
+
+    /// A whole-keyboard mapping with A4 (MIDI 69) at `frequency`.
+    fn kbm(frequency: &str) -> String {
+        format!("0\n0\n127\n60\n69\n{}\n12\n", frequency)
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-6 * b.abs().max(1.0)
+    }
+
+    /// The 12-TET scale with a 12-key mapping: middle C (MIDI 60) is the
+    /// root, `reference` is at `frequency` and each period is `degree`.
+    fn mapped(
+        entries: &str,
+        reference: u32,
+        frequency: f64,
+        degree: u32,
+    ) -> Result<Tuning, String> {
+        let count = entries.split_whitespace().count();
+        let kbm = format!(
+            "! test.kbm\n{}\n0\n127\n60\n{}\n{}\n{}\n! mapping\n{}\n",
+            count,
+            reference,
+            frequency,
+            degree,
+            entries.split_whitespace().collect::<Vec<_>>().join("\n")
+        );
+        Tuning::scala(SCL_12TET, Some(&kbm), 440.0)
+    }
+
+    #[test]
+    fn parses_cents_and_ratios() {
+        let scl = "! odd.scl\n!\nAn odd scale\n 4\n! pitches\n150.0 a label\n3/2\n5\n1200.000\n";
+        let (name, ratios) = parse_scl(scl).unwrap();
+        assert_eq!(name, "An odd scale");
+        assert_eq!(ratios.len(), 4);
+        assert!(close(ratios[0], 2f64.powf(150.0 / 1200.0)));
+        assert_eq!(&ratios[1..], &[1.5, 5.0, 2.0]);
+    }
+
+    #[test]
+    fn rejects_broken_scl_files() {
+        for scl in &[
+            "",
+            "no count\n",
+            "zero\n0\n",
+            "short\n3\n100.\n2/1\n",
+            "word\n1\nfifth\n",
+            "nothing over\n1\n3/0\n",
+            "negative\n1\n-3/2\n",
+            "huge\n1\n1e999\n",
+        ] {
+            assert!(parse_scl(scl).is_err(), "{:?}", scl);
+        }
+    }
+
+    #[test]
+    fn unmapped_keys_have_no_pitch() {
+        let white = mapped("0 x 2 x 4 5 x 7 x 9 x 11", 69, 440.0, 12).unwrap();
+        assert!(close(white.frequency(40).unwrap(), 261.625_565));
+        assert_eq!(white.frequency(41), None);
+        assert!(close(white.frequency(49).unwrap(), 440.0));
+        assert_eq!(white.frequency(50), None);
+        assert!(close(white.frequency(52).unwrap(), 523.251_131));
+        assert_eq!(white.step(40, true), Some(42));
+        assert_eq!(white.step(42, false), Some(40));
+    }
+
+    #[test]
+    fn mapping_repeats_by_the_period_degree() {
+        // A single entry repeated every key by a fifth.
+        let fifths = mapped("0", 60, 100.0, 7).unwrap();
+        let fifth = 2f64.powf(7.0 / 12.0);
+        assert!(close(fifths.frequency(40).unwrap(), 100.0));
+        assert!(close(fifths.frequency(41).unwrap(), 100.0 * fifth));
+        assert!(close(fifths.frequency(38).unwrap(), 100.0 / fifth / fifth));
+        // Degree 0 means the scale's own period.
+        let octaves = mapped("0", 60, 100.0, 0).unwrap();
+        assert!(close(octaves.frequency(41).unwrap(), 200.0));
+    }
+
+    #[test]
+    fn reference_key_sets_the_pitch() {
+        let tuning = mapped("0 1 2 3 4 5 6 7 8 9 10 11", 60, 256.0, 12).unwrap();
+        assert_eq!(tuning.reference_key, 40);
+        assert!(close(tuning.frequency(40).unwrap(), 256.0));
+        assert!(close(
+            tuning.frequency(49).unwrap(),
+            256.0 * 2f64.powf(0.75)
+        ));
+        assert!(mapped("0 x 2 x 4 5 x 7 x 9 x 11", 61, 440.0, 12).is_err());
+        assert!(mapped("0 13", 60, 440.0, 12).is_err());
+    }
+
+    #[test]
+    fn just_intonation() {
+        let just = Tuning::just(440.0);
+        let c4 = just.frequency(40).unwrap();
+        assert!(close(c4, 264.0));
+        assert!(close(just.frequency(44).unwrap(), 330.0));
+        assert!(close(just.frequency(47).unwrap(), 396.0));
+        assert!(close(just.frequency(52).unwrap(), 528.0));
+    }
+
+    #[test]
+    fn pythagorean_fifths_are_pure() {
+        let pythagorean = Tuning::pythagorean(440.0);
+        let c4 = pythagorean.frequency(40).unwrap();
+        assert!(close(c4, 440.0 * 16.0 / 27.0));
+        assert!(close(pythagorean.frequency(47).unwrap(), c4 * 1.5));
+        assert!(close(pythagorean.frequency(44).unwrap(), c4 * 81.0 / 64.0));
+        // G# is eight fifths up, a Pythagorean comma above Ab.
+        assert!(close(
+            pythagorean.frequency(48).unwrap(),
+            c4 * 6561.0 / 4096.0
+        ));
+    }
+
+    #[test]
+    fn meantone_thirds_are_pure() {
+        let meantone = Tuning::meantone(440.0);
+        let c4 = meantone.frequency(40).unwrap();
+        assert!(close(meantone.frequency(44).unwrap(), c4 * 1.25));
+        assert!(close(meantone.frequency(47).unwrap(), c4 * 5f64.powf(0.25)));
+        assert!(close(meantone.frequency(49).unwrap(), 440.0));
+    }
+
+    #[test]
+    fn kbm_reference_frequency_must_be_a_pitch() {
+        assert!(Tuning::scala(SCL_12TET, Some(&kbm("432.0")), 440.0).is_ok());
+        for frequency in &["0", "-440", "NaN", "inf", "1e999"] {
+            assert!(
+                Tuning::scala(SCL_12TET, Some(&kbm(frequency)), 440.0).is_err(),
+                "{}",
+                frequency
+            );
+        }
+    }
+
+    #[test]
+    fn nearest_key() {
+        let tuning = Tuning::default();
+        let note = tuning.nearest(441.0);
+        assert_eq!(note.key, piano::A4);
+        assert!((note.cents - 3.9).abs() < 0.1);
+    }
+
+    #[test]
+    fn nearest_ignores_non_finite_frequencies() {
+        let tuning = Tuning::default();
+        for &f in [::std::f64::NAN, ::std::f64::INFINITY, 0.0, -1.0].iter() {
+            tuning.nearest(f);
+        }
+    }
+}
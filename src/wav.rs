@@ -0,0 +1,164 @@
+/// How samples are stored in a WAV file.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Format {
+    Int16,
+    Int24,
+    Float32,
+}
+
+impl Format {
+    /// The format with `bits` per sample; 32 bits are floating point.
+    pub fn from_bits(bits: u32) -> Option<Format> {
+        match bits {
+            16 => Some(Format::Int16),
+            24 => Some(Format::Int24),
+            32 => Some(Format::Float32),
+            _ => None,
+        }
+    }
+
+    fn bytes(self) -> u32 {
+        match self {
+            Format::Int16 => 2,
+            Format::Int24 => 3,
+            Format::Float32 => 4,
+        }
+    }
+}
+
+/// Scales `samples` down so that none is louder than `peak`, leaving quieter
+/// ones as they are. Mixes of several voices can add up past full scale.
+pub fn limit(samples: &mut [f32], peak: f32) {
+    let loudest = samples
+        .iter()
+        .filter(|s| s.is_finite())
+        .fold(0.0f32, |loudest, s| loudest.max(s.abs()));
+    if loudest > peak {
+        let scale = peak / loudest;
+        for sample in samples.iter_mut() {
+            *sample *= scale;
+        }
+    }
+}
+
+/// A mono WAV file of `samples`, which are clipped to [-1, 1].
+pub fn encode(samples: &[f32], sample_rate: u32, format: Format) -> Vec<u8> {
+    let bytes = format.bytes();
+    let length = samples.len() as u32 * bytes;
+    // Formats other than integer PCM need an extended format chunk and a
+    // fact chunk with the length in frames.
+    let float = format == Format::Float32;
+    let header = if float { 4 + 26 + 12 + 8 } else { 4 + 24 + 8 };
+
+    let mut data = Vec::with_capacity(8 + header as usize + length as usize);
+    data.extend_from_slice(b"RIFF");
+    put_u32(&mut data, header + length);
+    data.extend_from_slice(b"WAVE");
+
+    data.extend_from_slice(b"fmt ");
+    put_u32(&mut data, if float { 18 } else { 16 });
+    put_u16(&mut data, if float { 3 } else { 1 });
+    // One channel.
+    put_u16(&mut data, 1);
+    put_u32(&mut data, sample_rate);
+    put_u32(&mut data, sample_rate * bytes);
+    put_u16(&mut data, bytes as u16);
+    put_u16(&mut data, bytes as u16 * 8);
+    if float {
+        put_u16(&mut data, 0);
+        data.extend_from_slice(b"fact");
+        put_u32(&mut data, 4);
+        put_u32(&mut data, samples.len() as u32);
+    }
+
+    data.extend_from_slice(b"data");
+    put_u32(&mut data, length);
+    for &sample in samples {
+        let sample = sample.max(-1.0).min(1.0);
+        match format {
+            Format::Int16 => put_u16(&mut data, (sample * 32767.0).round() as i16 as u16),
+            Format::Int24 => {
+                let value = (sample * 8388607.0).round() as i32 as u32;
+                data.extend_from_slice(&[value as u8, (value >> 8) as u8, (value >> 16) as u8]);
+            }
+            Format::Float32 => put_u32(&mut data, sample.to_bits()),
+        }
+    }
+    data
+}
+
+fn put_u16(data: &mut Vec<u8>, value: u16) {
+    data.extend_from_slice(&[value as u8, (value >> 8) as u8]);
+}
+
+fn put_u32(data: &mut Vec<u8>, value: u32) {
+    data.extend_from_slice(&[
+        value as u8,
+        (value >> 8) as u8,
+        (value >> 16) as u8,
+        (value >> 24) as u8,
+    ]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u16_at(data: &[u8], at: usize) -> u16 {
+        data[at] as u16 | (data[at + 1] as u16) << 8
+    }
+
+    fn u32_at(data: &[u8], at: usize) -> u32 {
+        u16_at(data, at) as u32 | (u16_at(data, at + 2) as u32) << 16
+    }
+
+    #[test]
+    fn encodes_16_bit_pcm() {
+        let data = encode(&[0.0, 0.5, -1.0, 2.0], 44100, Format::Int16);
+        assert_eq!(data.len(), 44 + 8);
+        assert_eq!(&data[0..4], b"RIFF");
+        assert_eq!(u32_at(&data, 4), 44 + 8 - 8);
+        assert_eq!(&data[8..16], b"WAVEfmt ");
+        assert_eq!(u16_at(&data, 20), 1);
+        assert_eq!(u16_at(&data, 22), 1);
+        assert_eq!(u32_at(&data, 24), 44100);
+        assert_eq!(u32_at(&data, 28), 88200);
+        assert_eq!(u16_at(&data, 34), 16);
+        assert_eq!(&data[36..40], b"data");
+        assert_eq!(u32_at(&data, 40), 8);
+        // Samples past full scale are clipped.
+        let samples = (0..4)
+            .map(|i| u16_at(&data, 44 + 2 * i) as i16)
+            .collect::<Vec<_>>();
+        assert_eq!(samples, vec![0, 16384, -32767, 32767]);
+    }
+
+    #[test]
+    fn encodes_24_bit_pcm() {
+        let data = encode(&[0.5, -0.5], 48000, Format::Int24);
+        assert_eq!(u16_at(&data, 34), 24);
+        assert_eq!(&data[44..], &[0x00, 0x00, 0x40, 0x00, 0x00, 0xc0]);
+    }
+
+    #[test]
+    fn encodes_float_with_a_fact_chunk() {
+        let data = encode(&[0.25, -0.75], 44100, Format::Float32);
+        assert_eq!(u16_at(&data, 20), 3);
+        assert_eq!(&data[38..42], b"fact");
+        assert_eq!(u32_at(&data, 46), 2);
+        assert_eq!(&data[50..54], b"data");
+        assert_eq!(u32_at(&data, 4) as usize, data.len() - 8);
+        assert_eq!(u32_at(&data, 58), 0.25f32.to_bits());
+        assert_eq!(u32_at(&data, 62), (-0.75f32).to_bits());
+    }
+
+    #[test]
+    fn limit_only_turns_down_loud_samples() {
+        let mut loud = vec![0.5, -2.0, 1.0];
+        limit(&mut loud, 0.9);
+        assert_eq!(loud, vec![0.225, -0.9, 0.45]);
+        let mut quiet = vec![0.5, -0.25];
+        limit(&mut quiet, 0.9);
+        assert_eq!(quiet, vec![0.5, -0.25]);
+    }
+}